@@ -0,0 +1,365 @@
+use std::{
+	collections::{HashMap, HashSet},
+	path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::daemon::capture::Direction;
+
+const MAGIC: &[u8] = b"btsnoop\0";
+/// PSM that AAP is spoken on.
+const AAP_PSM: u16 = 0x1001;
+const SIGNALING_CID: u16 = 0x0001;
+/// Microseconds from 0 AD, which btsnoop timestamps count from, to the unix epoch.
+const UNIX_EPOCH_OFFSET: i64 = 0x00dc_ddb3_0f2f_8000;
+
+/// How the packets of a capture are encapsulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Datalink {
+	/// HCI packets without a type, with the direction in the flags.
+	Hci,
+	/// HCI packets prefixed with their UART type, with the direction in the flags.
+	H4,
+	/// Linux monitor packets as written by `btmon -w`, with the opcode and adapter in the flags.
+	Monitor,
+}
+
+impl Datalink {
+	/// Returns the adapter, direction and contents of an ACL packet.
+	fn acl(self, flags: u32, data: Bytes) -> Option<(u16, Direction, Bytes)> {
+		let direction = |flags: u32| {
+			if flags & 1 == 0 {
+				Direction::Sent
+			} else {
+				Direction::Received
+			}
+		};
+		match self {
+			// bit 1 is set for commands and events
+			Self::Hci if flags & 2 == 0 => Some((0, direction(flags), data)),
+			Self::H4 if data.first() == Some(&0x02) => Some((0, direction(flags), data.slice(1..))),
+			Self::Monitor => match flags & 0xFFFF {
+				4 => Some(((flags >> 16) as u16, Direction::Sent, data)),
+				5 => Some(((flags >> 16) as u16, Direction::Received, data)),
+				_ => None,
+			},
+			_ => None,
+		}
+	}
+}
+
+/// An L2CAP payload of an AAP channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pdu {
+	/// Microseconds since the unix epoch.
+	pub time: i64,
+	pub direction: Direction,
+	pub data: Bytes,
+}
+
+fn opposite(direction: Direction) -> Direction {
+	match direction {
+		Direction::Sent => Direction::Received,
+		Direction::Received => Direction::Sent,
+	}
+}
+
+/// An ACL connection, identified by its adapter and connection handle.
+type Link = (u16, u16);
+
+/// Puts L2CAP PDUs back together from ACL fragments and follows the signaling channel to find
+/// the channels that AAP is spoken on.
+#[derive(Default)]
+struct Reassembler {
+	/// PDUs still waiting for continuation fragments, along with their full length.
+	partial: HashMap<(Link, Direction), (usize, BytesMut)>,
+	/// Connection requests by their identifier, with their direction, PSM and source CID.
+	requests: HashMap<(Link, u8), (Direction, u16, u16)>,
+	/// Destination CIDs of the AAP channels.
+	channels: HashSet<(Link, Direction, u16)>,
+}
+
+impl Reassembler {
+	/// Takes an ACL packet and returns the payload of the PDU it completes, if that PDU is on an
+	/// AAP channel.
+	fn acl(&mut self, adapter: u16, direction: Direction, mut data: Bytes) -> Option<Bytes> {
+		if data.remaining() < 4 {
+			return None;
+		}
+		let header = data.get_u16_le();
+		let link = (adapter, header & 0x0FFF);
+		let len = (data.get_u16_le() as usize).min(data.remaining());
+		let data = data.slice(..len);
+
+		let key = (link, direction);
+		let (expected, buf) = if (header >> 12) & 0b11 == 0b01 {
+			// continuation of an earlier fragment, which is lost if the capture started after it
+			let (expected, mut buf) = self.partial.remove(&key)?;
+			buf.extend_from_slice(&data);
+			(expected, buf)
+		} else {
+			// a new PDU drops whatever was left of the last one
+			self.partial.remove(&key);
+			if data.remaining() < 4 {
+				return None;
+			}
+			let expected = 4 + u16::from_le_bytes([data[0], data[1]]) as usize;
+			(expected, BytesMut::from(data.as_ref()))
+		};
+		if buf.len() < expected {
+			self.partial.insert(key, (expected, buf));
+			return None;
+		}
+
+		let mut pdu = buf.freeze().slice(..expected);
+		pdu.advance(2);
+		let cid = pdu.get_u16_le();
+		if cid == SIGNALING_CID {
+			self.signaling(link, direction, pdu);
+			None
+		} else if self.channels.contains(&(link, direction, cid)) {
+			Some(pdu)
+		} else {
+			None
+		}
+	}
+
+	fn signaling(&mut self, link: Link, direction: Direction, mut data: Bytes) {
+		while data.remaining() >= 4 {
+			let (code, id) = (data.get_u8(), data.get_u8());
+			let len = (data.get_u16_le() as usize).min(data.remaining());
+			let mut command = data.split_to(len);
+			match code {
+				// connection request
+				0x02 if command.remaining() >= 4 => {
+					let (psm, scid) = (command.get_u16_le(), command.get_u16_le());
+					self.requests.insert((link, id), (direction, psm, scid));
+				}
+				// connection response
+				0x03 if command.remaining() >= 6 => {
+					let (dcid, scid, result) = (
+						command.get_u16_le(),
+						command.get_u16_le(),
+						command.get_u16_le(),
+					);
+					let Some(&(request_direction, psm, request_scid)) =
+						self.requests.get(&(link, id))
+					else {
+						continue;
+					};
+					if request_direction == direction || request_scid != scid {
+						continue;
+					}
+					// 1 means that the connection is still pending
+					if result != 1 {
+						self.requests.remove(&(link, id));
+					}
+					if result == 0 && psm == AAP_PSM {
+						self.channels.insert((link, request_direction, dcid));
+						self.channels.insert((link, direction, scid));
+					}
+				}
+				// disconnection request
+				0x06 if command.remaining() >= 4 => {
+					let (dcid, scid) = (command.get_u16_le(), command.get_u16_le());
+					self.channels.remove(&(link, direction, dcid));
+					self.channels.remove(&(link, opposite(direction), scid));
+				}
+				_ => {}
+			}
+		}
+	}
+}
+
+/// Reads the L2CAP payloads that were sent on PSM 0x1001 in a btsnoop capture.
+pub fn read(path: &Path) -> Result<Vec<Pdu>> {
+	let data = std::fs::read(path)
+		.with_context(|| format!("failed to read capture file {}", path.display()))?;
+	parse(Bytes::from(data)).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn parse(mut data: Bytes) -> Result<Vec<Pdu>> {
+	if data.remaining() < 16 || !data.starts_with(MAGIC) {
+		bail!("not a btsnoop capture");
+	}
+	data.advance(MAGIC.len());
+	let version = data.get_u32();
+	if version != 1 {
+		bail!("unsupported btsnoop version {version}");
+	}
+	let datalink = match data.get_u32() {
+		1001 => Datalink::Hci,
+		1002 => Datalink::H4,
+		2001 => Datalink::Monitor,
+		x => bail!("unsupported btsnoop datalink type {x}"),
+	};
+
+	let mut reassembler = Reassembler::default();
+	let mut ret = Vec::new();
+	let mut offset = 16;
+	while data.has_remaining() {
+		if data.remaining() < 24 {
+			bail!("record at offset {offset} is cut off");
+		}
+		let _original_len = data.get_u32();
+		let len = data.get_u32() as usize;
+		let flags = data.get_u32();
+		let _drops = data.get_u32();
+		let time = data.get_i64() - UNIX_EPOCH_OFFSET;
+		if data.remaining() < len {
+			bail!("record at offset {offset} is cut off");
+		}
+		let packet = data.split_to(len);
+		offset += 24 + len;
+
+		if let Some((adapter, direction, acl)) = datalink.acl(flags, packet)
+			&& let Some(pdu) = reassembler.acl(adapter, direction, acl)
+		{
+			ret.push(Pdu {
+				time,
+				direction,
+				data: pdu,
+			});
+		}
+	}
+	Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+	use bytes::{BufMut, Bytes, BytesMut};
+
+	use super::{Pdu, UNIX_EPOCH_OFFSET, parse};
+	use crate::daemon::capture::Direction;
+
+	const TX: u32 = 4;
+	const RX: u32 = 5;
+	const HANDSHAKE: &[u8] = &[
+		0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00,
+	];
+	const EAR: &[u8] = &[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x02];
+
+	struct Capture(BytesMut);
+
+	impl Capture {
+		fn new(datalink: u32) -> Self {
+			let mut data = BytesMut::new();
+			data.put_slice(b"btsnoop\0");
+			data.put_u32(1);
+			data.put_u32(datalink);
+			Self(data)
+		}
+
+		fn record(&mut self, time: i64, flags: u32, packet: &[u8]) {
+			self.0.put_u32(packet.len() as u32);
+			self.0.put_u32(packet.len() as u32);
+			self.0.put_u32(flags);
+			self.0.put_u32(0);
+			self.0.put_i64(time + UNIX_EPOCH_OFFSET);
+			self.0.put_slice(packet);
+		}
+
+		/// Adds an ACL packet with the L2CAP PDU for `cid`, split into fragments of `mtu` bytes.
+		fn l2cap(&mut self, time: i64, flags: u32, cid: u16, payload: &[u8], mtu: usize) {
+			let mut pdu = BytesMut::new();
+			pdu.put_u16_le(payload.len() as u16);
+			pdu.put_u16_le(cid);
+			pdu.put_slice(payload);
+			for (i, fragment) in pdu.chunks(mtu).enumerate() {
+				let mut acl = BytesMut::new();
+				// handle 0x0B, start or continuation
+				acl.put_u16_le(0x000B | if i == 0 { 0x2000 } else { 0x1000 });
+				acl.put_u16_le(fragment.len() as u16);
+				acl.put_slice(fragment);
+				self.record(time, flags, &acl);
+			}
+		}
+
+		fn connect(&mut self) {
+			// request for PSM 0x1001 from CID 0x40, answered with CID 0x41
+			self.l2cap(
+				0,
+				TX,
+				1,
+				&[0x02, 0x07, 0x04, 0x00, 0x01, 0x10, 0x40, 0x00],
+				64,
+			);
+			self.l2cap(
+				1,
+				RX,
+				1,
+				&[
+					0x03, 0x07, 0x08, 0x00, 0x41, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,
+				],
+				64,
+			);
+		}
+	}
+
+	#[test]
+	fn monitor() {
+		let mut capture = Capture::new(2001);
+		// before the connection is set up
+		capture.l2cap(0, RX, 0x40, EAR, 64);
+		capture.connect();
+		capture.l2cap(2, TX, 0x41, HANDSHAKE, 64);
+		capture.l2cap(3, RX, 0x40, EAR, 5);
+		// another channel
+		capture.l2cap(4, RX, 0x42, EAR, 64);
+		// commands and events
+		capture.record(5, 2, &[0x01, 0x02]);
+
+		assert_eq!(
+			parse(capture.0.freeze()).unwrap(),
+			[
+				Pdu {
+					time: 2,
+					direction: Direction::Sent,
+					data: Bytes::from_static(HANDSHAKE),
+				},
+				Pdu {
+					time: 3,
+					direction: Direction::Received,
+					data: Bytes::from_static(EAR),
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn h4() {
+		let mut capture = Capture::new(1002);
+		let mut h4 = Capture::new(2001);
+		h4.connect();
+		h4.l2cap(2, RX, 0x40, EAR, 64);
+		// turn the monitor records into H4 ones
+		let mut data = h4.0.freeze().slice(16..);
+		while !data.is_empty() {
+			let len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+			let flags = u32::from_be_bytes(data[8..12].try_into().unwrap());
+			let time = i64::from_be_bytes(data[16..24].try_into().unwrap()) - UNIX_EPOCH_OFFSET;
+			let packet = [&[0x02], &data[24..24 + len]].concat();
+			capture.record(time, if flags == RX { 1 } else { 0 }, &packet);
+			data = data.slice(24 + len..);
+		}
+
+		let pdus = parse(capture.0.freeze()).unwrap();
+		assert_eq!(pdus.len(), 1);
+		assert_eq!(pdus[0].data.as_ref(), EAR);
+	}
+
+	#[test]
+	fn invalid_captures() {
+		assert!(parse(Bytes::from_static(b"not a capture at all")).is_err());
+		let mut capture = Capture::new(1);
+		assert!(parse(capture.0.clone().freeze()).is_err());
+
+		capture = Capture::new(2001);
+		capture.record(0, RX, EAR);
+		let data = capture.0.freeze();
+		assert!(parse(data.slice(..data.len() - 1)).is_err());
+	}
+}
@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::Decoder;
+
+use crate::daemon::{
+	capture::Direction,
+	codec::AapCodec,
+	packet::{OutgoingPacket, ParsedPacket},
+};
+
+use super::{btsnoop, history::format_time};
+
+/// Splits an L2CAP payload into AAP frames, in case a payload carries more than one.
+fn frames(data: Bytes) -> Result<Vec<Bytes>> {
+	let (mut codec, mut buf) = (AapCodec, BytesMut::from(data.as_ref()));
+	let mut ret = Vec::new();
+	while let Some(frame) = codec
+		.decode_eof(&mut buf)
+		.context("failed to split frames")?
+	{
+		ret.push(frame);
+	}
+	Ok(ret)
+}
+
+/// Opcode of a frame with the usual header, which tells the packet types apart.
+pub(super) fn opcode(frame: &[u8]) -> Option<u16> {
+	frame.get(4..6).map(|x| u16::from_le_bytes([x[0], x[1]]))
+}
+
+fn hex_dump(data: &[u8]) {
+	for (i, line) in data.chunks(16).enumerate() {
+		let hex: Vec<String> = line.iter().map(|x| format!("{x:02x}")).collect();
+		println!("\t{:04x}  {}", i * 16, hex.join(" "));
+	}
+}
+
+/// Describes a frame, or says why it wasn't understood.
+fn describe(direction: Direction, frame: Bytes) -> std::result::Result<String, String> {
+	let unknown = || match opcode(&frame) {
+		Some(x) => format!("unknown opcode 0x{x:04x}"),
+		None => "unknown packet".to_string(),
+	};
+	match direction {
+		Direction::Received => match ParsedPacket::decode(frame.clone()) {
+			Ok(ParsedPacket::Unknown(_)) => Err(unknown()),
+			Ok(packet) => Ok(format!("{packet:?}")),
+			Err(err) => Err(format!("malformed packet: {err:#}")),
+		},
+		Direction::Sent => match OutgoingPacket::decode(frame.clone()) {
+			Ok(packet) => Ok(format!("{packet:?}")),
+			Err(_) => Err(unknown()),
+		},
+	}
+}
+
+/// Prints the AAP traffic in a btsnoop capture, such as one written by `btmon -w`.
+pub fn show(path: &Path) -> Result<()> {
+	let pdus = btsnoop::read(path)?;
+	if pdus.is_empty() {
+		println!(
+			"No L2CAP connection on PSM 0x1001 found, the capture has to include the connection being set up."
+		);
+		return Ok(());
+	}
+
+	let (mut total, mut unknown) = (0, 0);
+	for pdu in pdus {
+		let time = format!(
+			"{}.{:03}",
+			format_time(pdu.time.div_euclid(1_000_000) as u64),
+			pdu.time.rem_euclid(1_000_000) / 1000
+		);
+		let direction = match pdu.direction {
+			Direction::Received => "device -> host",
+			Direction::Sent => "host -> device",
+		};
+		for frame in frames(pdu.data)? {
+			total += 1;
+			match describe(pdu.direction, frame.clone()) {
+				Ok(description) => println!("{time}  {direction}  {description}"),
+				Err(reason) => {
+					unknown += 1;
+					println!("{time}  {direction}  {reason}");
+					hex_dump(&frame);
+				}
+			}
+		}
+	}
+
+	println!();
+	println!("{total} frames, {unknown} not understood.");
+	Ok(())
+}
@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::daemon::{Address, history, packet::BatteryStatus};
+
+use super::history::{Component, format_time, local_time};
+
+/// Smallest drop in percent for a discharge to count towards the runtime.
+const MIN_DROP: u8 = 20;
+/// Longest time between two samples of one discharge. History has no samples while the device is
+/// disconnected, so a longer gap means that the levels on either side weren't seen in one session.
+const MAX_GAP: u64 = 60 * 60;
+const MONTH: f64 = 30.0 * 24.0 * 60.0 * 60.0;
+
+/// Uninterrupted discharge between two level changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Discharge {
+	start: u64,
+	end: u64,
+	drop: u8,
+}
+
+impl Discharge {
+	/// Seconds that a full charge would last at the rate of this discharge.
+	fn runtime(&self) -> f64 {
+		(self.end - self.start) as f64 * 100.0 / self.drop as f64
+	}
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct ComponentHealth {
+	/// Total discharge in units of a full charge.
+	cycles: f64,
+	/// Charges that ended at 100%.
+	full_charges: u32,
+	discharges: Vec<Discharge>,
+}
+
+impl ComponentHealth {
+	fn from_samples(samples: impl IntoIterator<Item = (u64, BatteryStatus)>) -> Self {
+		let mut ret = Self::default();
+		let mut discharged = 0u32;
+		// level last seen while discharging, and the first level change since
+		let mut last: Option<u8> = None;
+		let mut first_change: Option<(u64, u8)> = None;
+		let mut last_change: Option<(u64, u8)> = None;
+		let mut charging_from: Option<u8> = None;
+		let mut last_time: Option<u64> = None;
+
+		let end = |discharges: &mut Vec<Discharge>,
+		           first: &mut Option<(u64, u8)>,
+		           last: &mut Option<(u64, u8)>| {
+			if let (Some((start, from)), Some((end, to))) = (first.take(), last.take())
+				&& from - to >= MIN_DROP
+			{
+				discharges.push(Discharge {
+					start,
+					end,
+					drop: from - to,
+				});
+			}
+		};
+
+		for (time, status) in samples {
+			if last_time.is_some_and(|x| time.saturating_sub(x) > MAX_GAP) {
+				end(&mut ret.discharges, &mut first_change, &mut last_change);
+			}
+			last_time = Some(time);
+			match status {
+				BatteryStatus::Discharging(level) => {
+					charging_from = None;
+					match last {
+						Some(prev) if level < prev => {
+							discharged += (prev - level) as u32;
+							if first_change.is_none() {
+								first_change = Some((time, level));
+							}
+							last_change = Some((time, level));
+						}
+						Some(prev) if level > prev => {
+							end(&mut ret.discharges, &mut first_change, &mut last_change)
+						}
+						_ => {}
+					}
+					last = Some(level);
+				}
+				BatteryStatus::Charging(level) => {
+					end(&mut ret.discharges, &mut first_change, &mut last_change);
+					last = None;
+					match charging_from {
+						Some(from) if level >= 100 && from < 100 => {
+							ret.full_charges += 1;
+							charging_from = Some(level);
+						}
+						Some(_) => {}
+						None => charging_from = Some(level),
+					}
+				}
+				BatteryStatus::Unknown | BatteryStatus::Disconnected => {
+					end(&mut ret.discharges, &mut first_change, &mut last_change);
+					last = None;
+				}
+			}
+		}
+		end(&mut ret.discharges, &mut first_change, &mut last_change);
+
+		ret.cycles = discharged as f64 / 100.0;
+		ret
+	}
+
+	fn average_runtime(&self) -> Option<f64> {
+		if self.discharges.is_empty() {
+			return None;
+		}
+		Some(
+			self.discharges.iter().map(|x| x.runtime()).sum::<f64>() / self.discharges.len() as f64,
+		)
+	}
+
+	/// Least-squares change of the runtime per month, relative to the average runtime.
+	fn trend(&self) -> Option<f64> {
+		if self.discharges.len() < 2 {
+			return None;
+		}
+		let n = self.discharges.len() as f64;
+		let points: Vec<(f64, f64)> = self
+			.discharges
+			.iter()
+			.map(|x| (x.start as f64 / MONTH, x.runtime()))
+			.collect();
+		let mean_x = points.iter().map(|x| x.0).sum::<f64>() / n;
+		let mean_y = points.iter().map(|x| x.1).sum::<f64>() / n;
+		let var = points.iter().map(|x| (x.0 - mean_x).powi(2)).sum::<f64>();
+		if var == 0.0 || mean_y == 0.0 {
+			return None;
+		}
+		let cov = points
+			.iter()
+			.map(|x| (x.0 - mean_x) * (x.1 - mean_y))
+			.sum::<f64>();
+		Some(cov / var / mean_y)
+	}
+
+	/// Average runtime by month, keyed by `YYYY-MM`.
+	fn monthly_runtime(&self) -> BTreeMap<String, f64> {
+		let mut months: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+		for discharge in &self.discharges {
+			let month = local_time(discharge.start).map_or_else(
+				|| "unknown".to_string(),
+				|x| format!("{:04}-{:02}", x.tm_year + 1900, x.tm_mon + 1),
+			);
+			months.entry(month).or_default().push(discharge.runtime());
+		}
+		months
+			.into_iter()
+			.map(|(k, v)| (k, v.iter().sum::<f64>() / v.len() as f64))
+			.collect()
+	}
+}
+
+fn format_runtime(secs: Option<f64>) -> String {
+	match secs {
+		Some(secs) => {
+			let mins = (secs / 60.0).round() as u64;
+			format!("{}h {:02}m", mins / 60, mins % 60)
+		}
+		None => "-".to_string(),
+	}
+}
+
+pub fn show(addr: Address) -> Result<()> {
+	let records = history::read(addr, 0)?;
+	let Some(first) = records.first() else {
+		println!("No battery history recorded for {addr} yet.");
+		return Ok(());
+	};
+
+	let components = [Component::Case, Component::Left, Component::Right];
+	let health: Vec<ComponentHealth> = components
+		.iter()
+		.map(|component| {
+			ComponentHealth::from_samples(records.iter().map(|x| (x.time, component.get(x))))
+		})
+		.collect();
+
+	println!("Battery health for device {addr}:");
+	print!("{:<24}", "");
+	for component in &components {
+		print!("{:>12}", component.name());
+	}
+	println!();
+
+	let row = |name: &str, values: Vec<String>| {
+		print!("{:<24}", name);
+		for value in values {
+			print!("{:>12}", value);
+		}
+		println!();
+	};
+	row(
+		"Charge cycles",
+		health.iter().map(|x| format!("{:.1}", x.cycles)).collect(),
+	);
+	row(
+		"Full charges",
+		health.iter().map(|x| x.full_charges.to_string()).collect(),
+	);
+	row(
+		"Runtime per full charge",
+		health
+			.iter()
+			.map(|x| format_runtime(x.average_runtime()))
+			.collect(),
+	);
+	row(
+		"Runtime trend per month",
+		health
+			.iter()
+			.map(|x| {
+				x.trend()
+					.map_or_else(|| "-".to_string(), |x| format!("{:+.1}%", x * 100.0))
+			})
+			.collect(),
+	);
+
+	let monthly: Vec<BTreeMap<String, f64>> = health.iter().map(|x| x.monthly_runtime()).collect();
+	let mut months: Vec<&String> = monthly.iter().flat_map(|x| x.keys()).collect();
+	months.sort();
+	months.dedup();
+	if !months.is_empty() {
+		println!();
+		println!("Runtime per full charge by month:");
+		for month in months {
+			row(
+				month,
+				monthly
+					.iter()
+					.map(|x| format_runtime(x.get(month).copied()))
+					.collect(),
+			);
+		}
+	}
+
+	println!();
+	println!(
+		"Based on {} records since {}.",
+		records.len(),
+		format_time(first.time)
+	);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ComponentHealth, Discharge};
+	use crate::daemon::packet::BatteryStatus::{self, *};
+
+	/// A discharge from 100% to `to` taking `secs_per_step` for every 5%, followed by a charge.
+	fn cycle(start: u64, secs_per_step: u64, to: u8) -> Vec<(u64, BatteryStatus)> {
+		let mut ret = vec![(start, Discharging(100))];
+		let mut level = 100;
+		let mut time = start;
+		while level > to {
+			level -= 5;
+			time += secs_per_step;
+			ret.push((time, Discharging(level)));
+		}
+		ret.push((time + 60, Charging(level)));
+		ret.push((time + 3600, Charging(100)));
+		ret
+	}
+
+	#[test]
+	fn discharges() {
+		let samples = [cycle(0, 600, 50), cycle(100_000, 600, 90)].concat();
+		let health = ComponentHealth::from_samples(samples);
+
+		// the first step is not measured as 100% may have been reached long before
+		assert_eq!(
+			health.discharges,
+			[Discharge {
+				start: 600,
+				end: 6000,
+				drop: 45,
+			}]
+		);
+		assert_eq!(health.average_runtime(), Some(12000.0));
+		assert_eq!(health.full_charges, 2);
+		assert_eq!(health.cycles, 0.6);
+		assert_eq!(health.trend(), None);
+	}
+
+	#[test]
+	fn degrading_trend() {
+		let month = 30 * 24 * 60 * 60;
+		let samples = [
+			cycle(0, 600, 50),
+			cycle(month, 540, 50),
+			cycle(2 * month, 480, 50),
+		]
+		.concat();
+		let health = ComponentHealth::from_samples(samples);
+
+		assert_eq!(health.discharges.len(), 3);
+		let trend = health.trend().unwrap();
+		assert!((-0.12..-0.10).contains(&trend), "{trend}");
+	}
+
+	#[test]
+	fn bumps_split_discharges() {
+		let samples = [
+			(0, Discharging(80)),
+			(100, Discharging(75)),
+			(200, Discharging(70)),
+			(300, Discharging(75)),
+			(400, Discharging(70)),
+			(500, Discharging(65)),
+		];
+		let health = ComponentHealth::from_samples(samples);
+		assert!(health.discharges.is_empty());
+		assert_eq!(health.cycles, 0.2);
+	}
+
+	#[test]
+	fn disconnects_split_discharges() {
+		// 20% in 20 minutes, then disconnected for a day and another 20% in 20 minutes
+		let samples = [
+			(0, Discharging(90)),
+			(600, Discharging(80)),
+			(1200, Discharging(70)),
+			(1800, Discharging(60)),
+			(88_200, Discharging(50)),
+			(88_800, Discharging(40)),
+			(89_400, Discharging(30)),
+			(90_000, Discharging(20)),
+		];
+		let health = ComponentHealth::from_samples(samples);
+		assert_eq!(
+			health.discharges,
+			[
+				Discharge {
+					start: 600,
+					end: 1800,
+					drop: 20,
+				},
+				Discharge {
+					start: 88_200,
+					end: 90_000,
+					drop: 30,
+				},
+			]
+		);
+		assert_eq!(health.cycles, 0.7);
+	}
+}
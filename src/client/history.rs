@@ -0,0 +1,197 @@
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use serde_json::{Map, Value, json};
+
+use crate::daemon::{
+	Address,
+	history::{self, Record},
+	packet::BatteryStatus,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Component {
+	Case,
+	Left,
+	Right,
+}
+
+impl Component {
+	pub(super) fn name(&self) -> &'static str {
+		match self {
+			Self::Case => "case",
+			Self::Left => "left",
+			Self::Right => "right",
+		}
+	}
+
+	pub(super) fn get(&self, record: &Record) -> BatteryStatus {
+		match self {
+			Self::Case => record.battery.case,
+			Self::Left => record.battery.left,
+			Self::Right => record.battery.right,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Format {
+	#[default]
+	Table,
+	Csv,
+	Json,
+}
+
+/// Parses durations such as `30m`, `12h` or `7d` into seconds.
+pub fn parse_duration(s: &str) -> Result<u64> {
+	let (number, unit) = s.split_at(s.find(|x: char| !x.is_ascii_digit()).unwrap_or(s.len()));
+	let number: u64 = number
+		.parse()
+		.with_context(|| format!("invalid duration {s:?}, expected something like 7d"))?;
+	let unit = match unit {
+		"s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		"w" => 7 * 24 * 60 * 60,
+		x => bail!("invalid duration unit {x:?}, expected one of s, m, h, d or w"),
+	};
+	number
+		.checked_mul(unit)
+		.with_context(|| format!("duration {s:?} is too long"))
+}
+
+pub(super) fn local_time(time: u64) -> Option<libc::tm> {
+	let time = time as libc::time_t;
+	let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+	if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+		None
+	} else {
+		Some(tm)
+	}
+}
+
+pub(super) fn format_time(time: u64) -> String {
+	let Some(tm) = local_time(time) else {
+		return time.to_string();
+	};
+	format!(
+		"{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+		tm.tm_year + 1900,
+		tm.tm_mon + 1,
+		tm.tm_mday,
+		tm.tm_hour,
+		tm.tm_min,
+		tm.tm_sec
+	)
+}
+
+fn format_level(status: BatteryStatus) -> String {
+	match status {
+		BatteryStatus::Charging(x) => format!("{x}% charging"),
+		BatteryStatus::Discharging(x) => format!("{x}%"),
+		BatteryStatus::Disconnected | BatteryStatus::Unknown => "-".to_string(),
+	}
+}
+
+fn csv_fields(status: BatteryStatus) -> String {
+	match status.as_percent() {
+		Some(x) => format!("{x},{}", status.is_charging()),
+		None => ",".to_string(),
+	}
+}
+
+fn json_level(status: BatteryStatus) -> Value {
+	match status.as_percent() {
+		Some(x) => json!({ "level": x, "charging": status.is_charging() }),
+		None => Value::Null,
+	}
+}
+
+pub fn show(
+	addr: Address,
+	since: Option<u64>,
+	component: Option<Component>,
+	format: Format,
+) -> Result<()> {
+	let since = since.map_or(0, |x| history::now().saturating_sub(x));
+	let mut records = history::read(addr, since)?;
+
+	let components = match component {
+		Some(x) => vec![x],
+		None => vec![Component::Case, Component::Left, Component::Right],
+	};
+	// only show the changes of the selected component
+	if let Some(component) = component {
+		records.dedup_by(|a, b| component.get(a) == component.get(b));
+	}
+
+	match format {
+		Format::Table => {
+			print!("{:<19}", "time");
+			for component in &components {
+				print!("  {:<12}", component.name());
+			}
+			println!();
+			for record in &records {
+				print!("{:<19}", format_time(record.time));
+				for component in &components {
+					print!("  {:<12}", format_level(component.get(record)));
+				}
+				println!();
+			}
+		}
+		Format::Csv => {
+			print!("time");
+			for component in &components {
+				print!(",{0},{0}_charging", component.name());
+			}
+			println!();
+			for record in &records {
+				print!("{}", record.time);
+				for component in &components {
+					print!(",{}", csv_fields(component.get(record)));
+				}
+				println!();
+			}
+		}
+		Format::Json => {
+			let rows: Vec<Value> = records
+				.iter()
+				.map(|record| {
+					let mut row = Map::new();
+					row.insert("time".to_string(), record.time.into());
+					for component in &components {
+						row.insert(
+							component.name().to_string(),
+							json_level(component.get(record)),
+						);
+					}
+					Value::Object(row)
+				})
+				.collect();
+			println!(
+				"{}",
+				serde_json::to_string_pretty(&rows).context("failed to serialize history")?
+			);
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::parse_duration;
+
+	#[test]
+	fn durations() {
+		assert_eq!(parse_duration("45s").unwrap(), 45);
+		assert_eq!(parse_duration("30m").unwrap(), 30 * 60);
+		assert_eq!(parse_duration("12h").unwrap(), 12 * 60 * 60);
+		assert_eq!(parse_duration("7d").unwrap(), 7 * 24 * 60 * 60);
+		assert!(parse_duration("7").is_err());
+		assert!(parse_duration("d").is_err());
+		assert!(parse_duration("7y").is_err());
+		assert!(parse_duration("18446744073709551615w").is_err());
+	}
+}
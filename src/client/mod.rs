@@ -1,12 +1,113 @@
-use anyhow::{Context, Result};
-use tokio::net::UnixStream;
+use anyhow::{Context, Result, bail};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+	net::{
+		UnixStream,
+		unix::{OwnedReadHalf, OwnedWriteHalf},
+	},
+};
 
-use crate::daemon::Address;
+use crate::daemon::{
+	Address,
+	protocol::{Command, Event, PROTOCOL_VERSION, Request, Response, ServerMessage},
+};
 
+pub mod btsnoop;
+pub mod decode;
+pub mod health;
+pub mod history;
+pub mod noise;
+pub mod parse;
+pub mod policy;
+pub mod simulate;
 pub mod status;
 
-async fn connect(addr: Address) -> Result<UnixStream> {
-	UnixStream::connect(format!("\0dev.r58playz.airpodsd.{addr}"))
+struct Connection {
+	rx: Lines<BufReader<OwnedReadHalf>>,
+	tx: OwnedWriteHalf,
+	next_id: u64,
+}
+
+impl Connection {
+	async fn read_message(&mut self) -> Result<Option<ServerMessage>> {
+		let Some(line) = self
+			.rx
+			.next_line()
+			.await
+			.context("failed to read message from daemon")?
+		else {
+			return Ok(None);
+		};
+
+		serde_json::from_str(&line)
+			.context("failed to deserialize message from daemon")
+			.map(Some)
+	}
+
+	async fn request(&mut self, command: Command) -> Result<Response> {
+		let id = self.next_id;
+		self.next_id += 1;
+
+		let mut line =
+			serde_json::to_vec(&Request { id, command }).context("failed to serialize request")?;
+		line.extend_from_slice(b"\n");
+		self.tx
+			.write_all(&line)
+			.await
+			.context("failed to send request to daemon")?;
+
+		loop {
+			match self.read_message().await? {
+				Some(ServerMessage::Response {
+					id: Some(x),
+					result,
+				}) if x == id => return result.context("daemon returned an error"),
+				Some(ServerMessage::Response { id: None, result }) => {
+					result.context("daemon could not parse request")?;
+				}
+				Some(_) => {}
+				None => bail!("daemon closed the connection without responding"),
+			}
+		}
+	}
+
+	async fn next_event(&mut self) -> Result<Option<Event>> {
+		loop {
+			match self.read_message().await? {
+				Some(ServerMessage::Event { event }) => return Ok(Some(event)),
+				Some(_) => {}
+				None => return Ok(None),
+			}
+		}
+	}
+
+	async fn close(mut self) -> Result<()> {
+		self.tx
+			.shutdown()
+			.await
+			.context("failed to close connection to daemon")
+	}
+}
+
+async fn connect(addr: Address) -> Result<Connection> {
+	let (rx, tx) = UnixStream::connect(format!("\0dev.r58playz.airpodsd.{addr}"))
+		.await
+		.context("failed to connect to daemon")?
+		.into_split();
+	let mut conn = Connection {
+		rx: BufReader::new(rx).lines(),
+		tx,
+		next_id: 0,
+	};
+
+	match conn
+		.request(Command::Hello {
+			version: PROTOCOL_VERSION,
+		})
 		.await
-		.context("failed to connect to daemon")
+		.context("failed to handshake with daemon")?
+	{
+		Response::Hello { version } if version == PROTOCOL_VERSION => Ok(conn),
+		x => bail!("daemon sent an unexpected handshake response: {:?}", x),
+	}
 }
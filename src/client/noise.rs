@@ -0,0 +1,41 @@
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+
+use crate::daemon::{
+	Address,
+	packet::NoiseControlStatus,
+	protocol::{Command, Response},
+};
+
+use super::connect;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum NoiseMode {
+	Off,
+	Anc,
+	Transparency,
+	Adaptive,
+}
+
+impl From<NoiseMode> for NoiseControlStatus {
+	fn from(value: NoiseMode) -> Self {
+		match value {
+			NoiseMode::Off => Self::Off,
+			NoiseMode::Anc => Self::NoiseCancellation,
+			NoiseMode::Transparency => Self::Transparency,
+			NoiseMode::Adaptive => Self::AdaptiveTransparency,
+		}
+	}
+}
+
+pub async fn set(addr: Address, mode: NoiseMode) -> Result<()> {
+	let mut conn = connect(addr).await?;
+	match conn
+		.request(Command::SetNoiseControl { mode: mode.into() })
+		.await?
+	{
+		Response::Ok => {}
+		x => bail!("daemon sent an unexpected response: {:?}", x),
+	}
+	conn.close().await
+}
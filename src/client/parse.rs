@@ -0,0 +1,94 @@
+use std::io::{BufRead, stdin};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+
+use crate::daemon::{
+	capture::parse_hex,
+	packet::{DecodeError, ParsedPacket},
+};
+
+use super::decode::opcode;
+
+fn spaced_hex(data: &[u8]) -> String {
+	let hex: Vec<String> = data.iter().map(|x| format!("{x:02x}")).collect();
+	hex.join(" ")
+}
+
+/// Decodes and prints a packet from the device, returning whether it decoded.
+fn parse(data: Bytes) -> bool {
+	println!("{}", spaced_hex(&data));
+	let packet = match ParsedPacket::decode(data.clone()) {
+		Ok(packet) => packet,
+		Err(err) => {
+			match err.downcast_ref::<DecodeError>() {
+				Some(err) => {
+					println!("{}^^", " ".repeat(err.offset * 3));
+					println!("error at byte {}: {:#}", err.offset, err.error);
+				}
+				None => println!("error: {err:#}"),
+			}
+			return false;
+		}
+	};
+	if let ParsedPacket::Unknown(_) = packet {
+		match opcode(&data) {
+			Some(x) => println!("unknown packet with opcode 0x{x:04x}"),
+			None => println!("unknown packet"),
+		}
+		return true;
+	}
+	println!("{packet:#?}");
+
+	// point out what the decoder skipped over or had to guess
+	let encoded = packet.to_bytes();
+	if let Some(i) = encoded.iter().zip(data.iter()).position(|(a, b)| a != b) {
+		println!(
+			"note: byte {i} is 0x{:02x} but encodes back as 0x{:02x}, so its meaning is a guess",
+			data[i], encoded[i]
+		);
+	}
+	if data.len() > encoded.len() {
+		println!(
+			"note: {} trailing bytes from byte {} were not decoded: {}",
+			data.len() - encoded.len(),
+			encoded.len(),
+			spaced_hex(&data[encoded.len()..])
+		);
+	}
+	true
+}
+
+/// Decodes the packet in `hex`, or every line of stdin if it is empty.
+pub fn run(hex: &[String]) -> Result<()> {
+	if !hex.is_empty() {
+		if !parse(parse_hex(&hex.join(" "))?) {
+			bail!("packet failed to decode");
+		}
+		return Ok(());
+	}
+
+	let (mut total, mut failed) = (0, 0);
+	for (i, line) in stdin().lock().lines().enumerate() {
+		let line = line.context("failed to read stdin")?;
+		let line = line.split('#').next().unwrap_or_default().trim();
+		if line.is_empty() {
+			continue;
+		}
+		if total > 0 {
+			println!();
+		}
+		total += 1;
+		match parse_hex(line) {
+			Ok(data) => failed += !parse(data) as u32,
+			Err(err) => {
+				println!("line {}: {:#}", i + 1, err);
+				failed += 1;
+			}
+		}
+	}
+	if failed > 0 {
+		bail!("{failed} of {total} packets failed to decode");
+	}
+	Ok(())
+}
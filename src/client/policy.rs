@@ -0,0 +1,22 @@
+use anyhow::{Result, bail};
+
+use crate::daemon::{
+	Address,
+	battery::BatteryPolicy,
+	protocol::{Command, Response},
+};
+
+use super::connect;
+
+pub async fn run(addr: Address, policy: Option<BatteryPolicy>) -> Result<()> {
+	let mut conn = connect(addr).await?;
+	let command = match policy {
+		Some(policy) => Command::SetBatteryPolicy { policy },
+		None => Command::GetBatteryPolicy,
+	};
+	match conn.request(command).await? {
+		Response::BatteryPolicy { policy } => println!("Battery policy: {:?}", policy),
+		x => bail!("daemon sent an unexpected response: {:?}", x),
+	}
+	conn.close().await
+}
@@ -0,0 +1,434 @@
+use std::{future::pending, io, path::Path, str::FromStr, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::Bytes;
+use clap::ValueEnum;
+use log::{LevelFilter, info, warn};
+use tokio::{
+	io::{AsyncBufReadExt, BufReader, stdin},
+	net::{UnixListener, UnixStream},
+	select,
+	sync::mpsc,
+	time::sleep,
+};
+
+use crate::daemon::{
+	Address, PodsBattery, PodsInEar,
+	capture::parse_hex,
+	packet::{
+		Battery, BatteryComponent, BatteryStatus, ControlCommand, EarDetectionStatus,
+		NoiseControlStatus, OutgoingPacket, ParsedPacket,
+	},
+	simulator::socket_name,
+	transport::{StreamTransport, Transport},
+};
+
+use super::{history::parse_duration, noise::NoiseMode};
+
+/// A line of a simulator script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+	/// `battery <case|left|right> <level|unknown|disconnected> [charging]`
+	Battery(BatteryComponent, BatteryStatus),
+	/// `noise <off|anc|transparency|adaptive>`
+	Noise(NoiseControlStatus),
+	/// `ear <primary> <secondary>`, each one of `in-ear`, `out-of-ear` or `in-case`
+	Ear(EarDetectionStatus, EarDetectionStatus),
+	/// `wait <duration>`, such as `wait 5s`
+	Wait(Duration),
+	/// `send <hex>`, sends raw bytes such as an unknown or malformed packet
+	Send(Bytes),
+	/// `disconnect`, drops the connection to the daemon which then connects again
+	Disconnect,
+}
+
+fn parse_ear(s: &str) -> Result<EarDetectionStatus> {
+	Ok(match s {
+		"in-ear" => EarDetectionStatus::InEar,
+		"out-of-ear" => EarDetectionStatus::OutOfEar,
+		"in-case" => EarDetectionStatus::InCase,
+		x => bail!("invalid ear status {x:?}, expected in-ear, out-of-ear or in-case"),
+	})
+}
+
+impl FromStr for Step {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let (command, rest) = s.split_once(' ').unwrap_or((s, ""));
+		let args: Vec<&str> = rest.split_whitespace().collect();
+		Ok(match (command, args.as_slice()) {
+			("battery", [component, level, rest @ ..]) => {
+				let component = match *component {
+					"case" => BatteryComponent::Case,
+					"left" => BatteryComponent::Left,
+					"right" => BatteryComponent::Right,
+					x => bail!("invalid component {x:?}, expected case, left or right"),
+				};
+				let status = match (*level, rest) {
+					("unknown", []) => BatteryStatus::Unknown,
+					("disconnected", []) => BatteryStatus::Disconnected,
+					(level, rest) => {
+						let level: u8 = level
+							.parse()
+							.ok()
+							.filter(|x| *x <= 100)
+							.ok_or_else(|| anyhow!("invalid battery level {level:?}"))?;
+						match rest {
+							[] => BatteryStatus::Discharging(level),
+							["charging"] => BatteryStatus::Charging(level),
+							x => bail!("unexpected arguments {x:?}"),
+						}
+					}
+				};
+				Self::Battery(component, status)
+			}
+			("noise", [mode]) => Self::Noise(
+				NoiseMode::from_str(mode, true)
+					.map_err(|x| anyhow!(x))?
+					.into(),
+			),
+			("ear", [primary, secondary]) => Self::Ear(parse_ear(primary)?, parse_ear(secondary)?),
+			("wait", [duration]) => Self::Wait(Duration::from_secs(parse_duration(duration)?)),
+			("send", _) => Self::Send(parse_hex(rest)?),
+			("disconnect", []) => Self::Disconnect,
+			("battery" | "noise" | "ear" | "wait" | "disconnect", _) => {
+				bail!("wrong number of arguments for {command}")
+			}
+			(x, _) => bail!("unknown command {x:?}"),
+		})
+	}
+}
+
+/// Parses a script line, which is `None` for empty lines and comments.
+fn parse_line(line: &str) -> Result<Option<Step>> {
+	let line = line.split('#').next().unwrap_or_default().trim();
+	if line.is_empty() {
+		return Ok(None);
+	}
+	line.parse().map(Some)
+}
+
+/// Reads a whole script, so that mistakes show up before the simulator starts.
+fn read_script(path: &Path) -> Result<Vec<Step>> {
+	let data = std::fs::read_to_string(path)
+		.with_context(|| format!("failed to read script {}", path.display()))?;
+	data.lines()
+		.enumerate()
+		.filter_map(|(i, line)| {
+			parse_line(line)
+				.with_context(|| format!("{}:{}", path.display(), i + 1))
+				.transpose()
+		})
+		.collect()
+}
+
+/// Hands out the steps of `script`, or the steps typed into stdin if there is none, sleeping
+/// through the waits.
+async fn feed_steps(script: Option<Vec<Step>>, tx: mpsc::Sender<Step>) -> Result<()> {
+	let wait_or_send = async |step| match step {
+		Step::Wait(x) => {
+			sleep(x).await;
+			true
+		}
+		step => tx.send(step).await.is_ok(),
+	};
+
+	if let Some(script) = script {
+		for step in script {
+			if !wait_or_send(step).await {
+				break;
+			}
+		}
+		return Ok(());
+	}
+
+	let mut lines = BufReader::new(stdin()).lines();
+	while let Some(line) = lines.next_line().await.context("failed to read stdin")? {
+		match parse_line(&line) {
+			Ok(Some(step)) => {
+				if !wait_or_send(step).await {
+					break;
+				}
+			}
+			Ok(None) => {}
+			Err(err) => eprintln!("{err:#}"),
+		}
+	}
+	Ok(())
+}
+
+/// State of the simulated device, which is sent to the daemon as it changes.
+struct Device {
+	battery: PodsBattery,
+	noise: NoiseControlStatus,
+	ear: PodsInEar,
+}
+
+impl Device {
+	fn battery_packet(&self) -> ParsedPacket {
+		ParsedPacket::Battery(vec![
+			Battery {
+				component: BatteryComponent::Left,
+				status: self.battery.left,
+			},
+			Battery {
+				component: BatteryComponent::Right,
+				status: self.battery.right,
+			},
+			Battery {
+				component: BatteryComponent::Case,
+				status: self.battery.case,
+			},
+		])
+	}
+
+	fn ear_packet(&self) -> ParsedPacket {
+		ParsedPacket::EarDetection {
+			primary: self.ear.primary,
+			secondary: self.ear.secondary,
+		}
+	}
+}
+
+struct Connection {
+	transport: StreamTransport,
+	/// Whether the daemon subscribed to notifications, before which nothing is sent.
+	subscribed: bool,
+}
+
+enum SimulatorEvent {
+	Accept(io::Result<UnixStream>),
+	Step(Option<Step>),
+	Read(io::Result<Option<Bytes>>),
+}
+
+async fn read(conn: &mut Option<Connection>) -> io::Result<Option<Bytes>> {
+	match conn {
+		Some(conn) => conn.transport.read_frame().await,
+		None => pending().await,
+	}
+}
+
+async fn send(conn: &mut Option<Connection>, packet: &ParsedPacket) {
+	let Some(connection) = conn.as_mut().filter(|x| x.subscribed) else {
+		return;
+	};
+	if let Err(err) = connection.transport.write_frame(packet.to_bytes()).await {
+		warn!("failed to send to daemon, dropping connection: {:?}", err);
+		conn.take();
+	}
+}
+
+async fn handle_step(device: &mut Device, conn: &mut Option<Connection>, step: Step) {
+	info!("{:?}", step);
+	match step {
+		Step::Battery(component, status) => {
+			match component {
+				BatteryComponent::Case => device.battery.case = status,
+				BatteryComponent::Left => device.battery.left = status,
+				BatteryComponent::Right => device.battery.right = status,
+			}
+			send(conn, &device.battery_packet()).await;
+		}
+		Step::Noise(mode) => {
+			device.noise = mode;
+			send(conn, &ParsedPacket::NoiseControl(mode)).await;
+		}
+		Step::Ear(primary, secondary) => {
+			device.ear = PodsInEar { primary, secondary };
+			send(conn, &device.ear_packet()).await;
+		}
+		Step::Send(raw) => send(conn, &ParsedPacket::Unknown(raw)).await,
+		Step::Disconnect => {
+			if let Some(mut conn) = conn.take()
+				&& let Err(err) = conn.transport.close().await
+			{
+				warn!("failed to close connection to daemon: {:?}", err);
+			}
+		}
+		// taken care of by feed_steps
+		Step::Wait(_) => {}
+	}
+}
+
+async fn handle_frame(device: &mut Device, conn: &mut Option<Connection>, frame: Bytes) {
+	match OutgoingPacket::decode(frame.clone()) {
+		Ok(OutgoingPacket::Handshake) => info!("daemon sent handshake"),
+		Ok(OutgoingPacket::EnableFeatures) => info!("daemon enabled features"),
+		Ok(OutgoingPacket::SubscribeNotifications) => {
+			info!("daemon subscribed to notifications, sending the current state");
+			if let Some(conn) = conn.as_mut() {
+				conn.subscribed = true;
+			}
+			for packet in [
+				device.battery_packet(),
+				ParsedPacket::NoiseControl(device.noise),
+				device.ear_packet(),
+			] {
+				send(conn, &packet).await;
+			}
+		}
+		Ok(OutgoingPacket::Control(ControlCommand::NoiseControl(mode))) => {
+			info!("daemon set noise control to {:?}", mode);
+			device.noise = mode;
+			// the device confirms the new mode like any other noise control change
+			send(conn, &ParsedPacket::NoiseControl(mode)).await;
+		}
+		Err(err) => warn!(
+			"failed to decode packet {:x?} from daemon: {:?}",
+			frame.as_ref(),
+			err
+		),
+	}
+}
+
+/// Plays the device with `addr` for `airpodsd daemon --simulate`, following the script at
+/// `script` or the steps typed into stdin.
+pub async fn run(addr: Address, script: Option<&Path>) -> Result<()> {
+	let script = script.map(read_script).transpose()?;
+
+	env_logger::builder()
+		.filter_level(LevelFilter::Info)
+		.parse_default_env()
+		.init();
+
+	let listener = UnixListener::bind(socket_name(addr))
+		.context("failed to bind simulator socket, is another simulator running?")?;
+	info!("simulating device {}", addr);
+
+	let (tx, mut steps) = mpsc::channel(16);
+	let mut feed = tokio::spawn(feed_steps(script, tx));
+
+	let mut device = Device {
+		battery: PodsBattery {
+			case: BatteryStatus::Discharging(60),
+			left: BatteryStatus::Discharging(100),
+			right: BatteryStatus::Discharging(100),
+		},
+		noise: NoiseControlStatus::NoiseCancellation,
+		ear: PodsInEar {
+			primary: EarDetectionStatus::InEar,
+			secondary: EarDetectionStatus::InEar,
+		},
+	};
+	let mut conn: Option<Connection> = None;
+	let mut steps_done = false;
+	loop {
+		match select! {
+			x = listener.accept() => SimulatorEvent::Accept(x.map(|x| x.0)),
+			x = steps.recv(), if !steps_done => SimulatorEvent::Step(x),
+			x = read(&mut conn) => SimulatorEvent::Read(x),
+		} {
+			SimulatorEvent::Accept(Ok(stream)) => {
+				if conn.is_some() {
+					warn!("another daemon connected, dropping the old connection");
+				}
+				info!("daemon connected");
+				conn = Some(Connection {
+					transport: stream.into(),
+					subscribed: false,
+				});
+			}
+			SimulatorEvent::Accept(Err(err)) => {
+				return Err(err).context("failed to accept connection from daemon");
+			}
+			SimulatorEvent::Step(Some(step)) => handle_step(&mut device, &mut conn, step).await,
+			SimulatorEvent::Step(None) => {
+				steps_done = true;
+				(&mut feed).await.context("step reader panicked")??;
+				info!("no more steps, keeping the device as it is");
+			}
+			SimulatorEvent::Read(Ok(Some(frame))) => {
+				handle_frame(&mut device, &mut conn, frame).await
+			}
+			SimulatorEvent::Read(Ok(None)) => {
+				info!("daemon disconnected");
+				conn = None;
+			}
+			SimulatorEvent::Read(Err(err)) => {
+				warn!("failed to read from daemon: {:?}", err);
+				conn = None;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use bytes::Bytes;
+
+	use super::{Step, parse_line};
+	use crate::daemon::packet::{
+		BatteryComponent, BatteryStatus, EarDetectionStatus, NoiseControlStatus,
+	};
+
+	#[test]
+	fn steps() {
+		assert_eq!(parse_line("  # comment").unwrap(), None);
+		assert_eq!(
+			parse_line("battery left 80 # after a while").unwrap(),
+			Some(Step::Battery(
+				BatteryComponent::Left,
+				BatteryStatus::Discharging(80)
+			))
+		);
+		assert_eq!(
+			parse_line("battery case 40 charging").unwrap(),
+			Some(Step::Battery(
+				BatteryComponent::Case,
+				BatteryStatus::Charging(40)
+			))
+		);
+		assert_eq!(
+			parse_line("battery right disconnected").unwrap(),
+			Some(Step::Battery(
+				BatteryComponent::Right,
+				BatteryStatus::Disconnected
+			))
+		);
+		assert_eq!(
+			parse_line("noise transparency").unwrap(),
+			Some(Step::Noise(NoiseControlStatus::Transparency))
+		);
+		assert_eq!(
+			parse_line("ear out-of-ear in-case").unwrap(),
+			Some(Step::Ear(
+				EarDetectionStatus::OutOfEar,
+				EarDetectionStatus::InCase
+			))
+		);
+		assert_eq!(
+			parse_line("wait 2m").unwrap(),
+			Some(Step::Wait(Duration::from_secs(120)))
+		);
+		assert_eq!(
+			parse_line("send 04 00 04 00 2b00").unwrap(),
+			Some(Step::Send(Bytes::from_static(&[
+				0x04, 0x00, 0x04, 0x00, 0x2B, 0x00
+			])))
+		);
+		assert_eq!(parse_line("disconnect").unwrap(), Some(Step::Disconnect));
+	}
+
+	#[test]
+	fn invalid_steps() {
+		for line in [
+			"battery left 101",
+			"battery left",
+			"battery top 50",
+			"battery left 50 charging now",
+			"noise loud",
+			"ear in-ear",
+			"wait 5",
+			"send 0",
+			"send zz",
+			"disconnect now",
+			"explode",
+		] {
+			assert!(parse_line(line).is_err(), "{line}");
+		}
+	}
+}
@@ -1,12 +1,29 @@
-use anyhow::{Context, Result};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use anyhow::{Result, bail};
 
-use crate::daemon::{Address, PodsStatus};
+use crate::daemon::{
+	Address, PodsStatus,
+	estimate::Estimate,
+	protocol::{Command, Event, Response},
+};
 
 use super::connect;
 
+fn format_estimate(estimate: Option<Estimate>) -> String {
+	let format = |secs: u64| format!("{}h {:02}m", secs / 3600, secs / 60 % 60);
+	match estimate {
+		Some(Estimate::TimeToEmpty(x)) => format!("{} left", format(x)),
+		Some(Estimate::TimeToFull(0)) => "full".to_string(),
+		Some(Estimate::TimeToFull(x)) => format!("full in {}", format(x)),
+		None => "unknown".to_string(),
+	}
+}
+
 fn print_status(addr: Address, status: PodsStatus) {
 	println!("Status for device {addr}:");
+	println!(
+		"\tConnected: {}",
+		if status.connected { "yes" } else { "no" }
+	);
 
 	if let Some(battery) = status.battery {
 		println!(
@@ -17,6 +34,19 @@ fn print_status(addr: Address, status: PodsStatus) {
 		println!("\tBattery: unknown");
 	}
 
+	let estimate = status.estimate;
+	if [estimate.case, estimate.left, estimate.right]
+		.iter()
+		.any(|x| x.is_some())
+	{
+		println!(
+			"\tRemaining: Case {} Left {} Right {}",
+			format_estimate(estimate.case),
+			format_estimate(estimate.left),
+			format_estimate(estimate.right)
+		);
+	}
+
 	if let Some(noise) = status.noise {
 		println!("\tNoise control: {:?}", noise);
 	} else {
@@ -34,36 +64,37 @@ fn print_status(addr: Address, status: PodsStatus) {
 }
 
 pub async fn get(addr: Address) -> Result<()> {
-	let sock = connect(addr).await?;
-	let mut sock = BufReader::new(sock).lines();
-	let status = sock
-		.next_line()
-		.await
-		.context("failed to read status from server")?
-		.context("server returned no status")?;
-	sock.into_inner()
-		.shutdown()
-		.await
-		.context("failed to close connection to daemon")?;
-	let decoded =
-		serde_json::from_str::<PodsStatus>(&status).context("failed to deserialize status")?;
+	let mut conn = connect(addr).await?;
+	let status = match conn.request(Command::GetStatus).await? {
+		Response::Status { status } => status,
+		x => bail!("daemon sent an unexpected response: {:?}", x),
+	};
+	conn.close().await?;
 
-	print_status(addr, decoded);
+	print_status(addr, status);
 	Ok(())
 }
 
 pub async fn watch(addr: Address) -> Result<()> {
-	let sock = connect(addr).await?;
-	let mut sock = BufReader::new(sock).lines();
-	while let Some(status) = sock
-		.next_line()
-		.await
-		.context("failed to read status from server")?
-	{
-		let decoded =
-			serde_json::from_str::<PodsStatus>(&status).context("failed to deserialize status")?;
-		print_status(addr, decoded);
-		println!();
+	let mut conn = connect(addr).await?;
+
+	// subscribe first so that no update between the snapshot and the subscription is lost
+	conn.request(Command::Subscribe).await?;
+	match conn.request(Command::GetStatus).await? {
+		Response::Status { status } => {
+			print_status(addr, status);
+			println!();
+		}
+		x => bail!("daemon sent an unexpected response: {:?}", x),
+	}
+
+	while let Some(event) = conn.next_event().await? {
+		match event {
+			Event::Status { status } => {
+				print_status(addr, status);
+				println!();
+			}
+		}
 	}
 	Ok(())
 }
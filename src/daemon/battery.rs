@@ -0,0 +1,192 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use super::{
+	PodsBattery, PodsInEar,
+	packet::EarDetectionStatus::{InCase, InEar},
+};
+
+/// How the battery levels of the buds and the case are combined into the single percentage
+/// reported to bluez.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum BatteryPolicy {
+	/// Average of both buds.
+	#[default]
+	Average,
+	/// Lowest of both buds.
+	Min,
+	/// Highest of both buds.
+	Max,
+	/// Left bud only.
+	Left,
+	/// Right bud only.
+	Right,
+	/// Lowest of the buds in an ear. Ear detection reports primary and secondary rather than left
+	/// and right, so this is the same as `Min` unless the other bud is charging in the case.
+	LowestInEar,
+	/// Average of both buds and the case.
+	IncludeCase,
+}
+
+fn average(levels: &[u8]) -> Option<u8> {
+	if levels.is_empty() {
+		return None;
+	}
+	let sum: u32 = levels.iter().map(|x| *x as u32).sum();
+	Some((sum / levels.len() as u32) as u8)
+}
+
+impl BatteryPolicy {
+	pub fn aggregate(&self, battery: &PodsBattery, ear: Option<PodsInEar>) -> Option<u8> {
+		let buds: Vec<u8> = [battery.left, battery.right]
+			.iter()
+			.filter_map(|x| x.as_percent())
+			.collect();
+
+		match self {
+			Self::Average => average(&buds),
+			Self::Min => buds.iter().min().copied(),
+			Self::Max => buds.iter().max().copied(),
+			Self::Left => battery.left.as_percent(),
+			Self::Right => battery.right.as_percent(),
+			Self::LowestInEar => {
+				let one_in_case = ear.is_some_and(|x| {
+					matches!((x.primary, x.secondary), (InEar, InCase) | (InCase, InEar))
+				});
+				// the bud in the case is the one that is charging
+				one_in_case
+					.then(|| {
+						[battery.left, battery.right]
+							.iter()
+							.filter(|x| !x.is_charging())
+							.filter_map(|x| x.as_percent())
+							.min()
+					})
+					.flatten()
+					.or_else(|| buds.iter().min().copied())
+			}
+			Self::IncludeCase => {
+				let all: Vec<u8> = [battery.left, battery.right, battery.case]
+					.iter()
+					.filter_map(|x| x.as_percent())
+					.collect();
+				average(&all)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::BatteryPolicy;
+	use crate::daemon::{
+		PodsBattery, PodsInEar,
+		packet::{
+			BatteryStatus::*,
+			EarDetectionStatus::{self, *},
+		},
+	};
+
+	/// Every policy except `IncludeCase`, which is the only one that looks at the case.
+	const BUD_POLICIES: [BatteryPolicy; 6] = [
+		BatteryPolicy::Average,
+		BatteryPolicy::Min,
+		BatteryPolicy::Max,
+		BatteryPolicy::Left,
+		BatteryPolicy::Right,
+		BatteryPolicy::LowestInEar,
+	];
+
+	/// Checks `policies` against `expected`, which lists their results in the same order with `-`
+	/// for none.
+	fn check(
+		policies: &[BatteryPolicy],
+		battery: PodsBattery,
+		ear: Option<PodsInEar>,
+		expected: &str,
+	) {
+		let expected: Vec<Option<u8>> = expected.split(' ').map(|x| x.parse().ok()).collect();
+		assert_eq!(expected.len(), policies.len(), "results for {:?}", policies);
+		for (policy, expected) in policies.iter().zip(expected) {
+			assert_eq!(
+				policy.aggregate(&battery, ear),
+				expected,
+				"{:?} with {:?} and {:?}",
+				policy,
+				battery,
+				ear
+			);
+		}
+	}
+
+	#[test]
+	fn every_combination() {
+		let cases = [Unknown, Disconnected, Charging(30), Discharging(30)];
+		let lefts = [Unknown, Disconnected, Charging(80), Discharging(80)];
+		let rights = [Unknown, Disconnected, Charging(55), Discharging(55)];
+		// results of `BUD_POLICIES`, then of `IncludeCase` for each of `cases`, for every left and
+		// right in order
+		let expected = [
+			"- - - - - - | - - 30 30",
+			"- - - - - - | - - 30 30",
+			"55 55 55 - 55 55 | 55 55 42 42",
+			"55 55 55 - 55 55 | 55 55 42 42",
+			"- - - - - - | - - 30 30",
+			"- - - - - - | - - 30 30",
+			"55 55 55 - 55 55 | 55 55 42 42",
+			"55 55 55 - 55 55 | 55 55 42 42",
+			"80 80 80 80 - 80 | 80 80 55 55",
+			"80 80 80 80 - 80 | 80 80 55 55",
+			"67 55 80 80 55 55 | 67 67 55 55",
+			"67 55 80 80 55 55 | 67 67 55 55",
+			"80 80 80 80 - 80 | 80 80 55 55",
+			"80 80 80 80 - 80 | 80 80 55 55",
+			"67 55 80 80 55 55 | 67 67 55 55",
+			"67 55 80 80 55 55 | 67 67 55 55",
+		];
+		assert_eq!(expected.len(), lefts.len() * rights.len());
+
+		let combinations = lefts.into_iter().flat_map(|x| rights.map(|y| (x, y)));
+		for ((left, right), expected) in combinations.zip(expected) {
+			let (buds, include_case) = expected.split_once(" | ").unwrap();
+			let include_case: Vec<&str> = include_case.split(' ').collect();
+			assert_eq!(include_case.len(), cases.len());
+			for (case, include_case) in cases.into_iter().zip(include_case) {
+				let battery = PodsBattery { case, left, right };
+				check(&BUD_POLICIES, battery, None, buds);
+				check(&[BatteryPolicy::IncludeCase], battery, None, include_case);
+			}
+		}
+	}
+
+	#[test]
+	fn lowest_in_ear() {
+		let check = |left, right, primary, secondary, expected: &str| {
+			let battery = PodsBattery {
+				case: Charging(30),
+				left,
+				right,
+			};
+			let ear = Some(PodsInEar { primary, secondary });
+			check(&[BatteryPolicy::LowestInEar], battery, ear, expected);
+		};
+		let statuses: [EarDetectionStatus; 3] = [InEar, OutOfEar, InCase];
+
+		// only a bud in an ear with the other one charging in the case can be told apart
+		check(Discharging(80), Charging(55), InEar, InCase, "80");
+		check(Discharging(80), Charging(55), InCase, InEar, "80");
+		check(Charging(80), Discharging(55), InEar, InCase, "55");
+		check(Discharging(80), Charging(55), InEar, OutOfEar, "55");
+		check(Discharging(80), Discharging(55), InEar, InCase, "55");
+		check(Charging(80), Charging(55), InEar, InCase, "55");
+		check(Discharging(80), Unknown, InEar, InCase, "80");
+		check(Unknown, Charging(55), InEar, InCase, "55");
+		for primary in statuses {
+			for secondary in statuses {
+				check(Discharging(80), Discharging(55), primary, secondary, "55");
+				check(Unknown, Disconnected, primary, secondary, "-");
+			}
+		}
+	}
+}
@@ -1,7 +1,7 @@
 use std::{
 	fmt::Display,
 	io::{Error, Result},
-	os::fd::{FromRawFd, OwnedFd},
+	os::fd::{AsRawFd, FromRawFd, OwnedFd},
 	str::FromStr,
 };
 
@@ -11,11 +11,14 @@ use libbluetooth::{
 	l2cap::sockaddr_l2,
 };
 use libc::sockaddr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
 use tokio::task::spawn_blocking;
 
+use super::transport::StreamTransport;
+
 const L2CAP_SOCKADDR_LEN: usize = size_of::<sockaddr_l2>();
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Address([u8; 6]);
 
 impl Address {
@@ -51,6 +54,28 @@ impl FromStr for Address {
 	}
 }
 
+impl From<bluer::Address> for Address {
+	fn from(value: bluer::Address) -> Self {
+		let mut addr = value.0;
+		addr.reverse();
+		Self(addr)
+	}
+}
+
+impl Serialize for Address {
+	fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+impl<'de> Deserialize<'de> for Address {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+		String::deserialize(deserializer)?
+			.parse()
+			.map_err(|x| D::Error::custom(format!("{x:#}")))
+	}
+}
+
 #[derive(Clone, Copy)]
 pub struct L2CapAddr(sockaddr_l2);
 
@@ -74,35 +99,45 @@ fn checkerr(err: i32) -> Result<i32> {
 	}
 }
 
-unsafe fn connect_inner_unsafe(addr: sockaddr_l2) -> Result<OwnedFd> {
+unsafe fn connect_inner_unsafe(local: sockaddr_l2, addr: sockaddr_l2) -> Result<OwnedFd> {
+	// owned right away so that the socket is closed if binding or connecting fails
 	let fd = unsafe {
-		checkerr(libc::socket(
+		OwnedFd::from_raw_fd(checkerr(libc::socket(
 			libc::AF_BLUETOOTH,
 			libc::SOCK_STREAM,
 			bluetooth::BTPROTO_L2CAP,
-		))?
+		))?)
 	};
 
+	unsafe {
+		checkerr(libc::bind(
+			fd.as_raw_fd(),
+			&local as *const sockaddr_l2 as *const sockaddr,
+			L2CAP_SOCKADDR_LEN as u32,
+		))?;
+	}
+
 	unsafe {
 		checkerr(libc::connect(
-			fd,
+			fd.as_raw_fd(),
 			&addr as *const sockaddr_l2 as *const sockaddr,
 			L2CAP_SOCKADDR_LEN as u32,
 		))?;
 	}
 
-	Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+	Ok(fd)
 }
 
-fn connect_inner(addr: L2CapAddr) -> Result<tokio::net::UnixStream> {
-	let fd = unsafe { connect_inner_unsafe(addr.0)? };
+fn connect_inner(local: L2CapAddr, addr: L2CapAddr) -> Result<tokio::net::UnixStream> {
+	let fd = unsafe { connect_inner_unsafe(local.0, addr.0)? };
 	let std = std::os::unix::net::UnixStream::from(fd);
 	std.set_nonblocking(true)?;
 	tokio::net::UnixStream::from_std(std)
 }
 
-pub async fn connect(addr: L2CapAddr) -> Result<tokio::net::UnixStream> {
-	spawn_blocking(move || {
-		connect_inner(addr)
-	}).await?
+/// Connects to `addr` from the adapter with the address in `local`.
+pub async fn connect(local: L2CapAddr, addr: L2CapAddr) -> Result<StreamTransport> {
+	spawn_blocking(move || connect_inner(local, addr))
+		.await?
+		.map(StreamTransport::from)
 }
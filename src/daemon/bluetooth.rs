@@ -1,102 +1,141 @@
-use anyhow::{Context, Result};
-use bluer::{Device, Session};
-use bytes::{Buf, Bytes};
+use anyhow::{Context, Result, bail};
+use bluer::{Adapter, Device, DeviceEvent, DeviceProperty, Session};
+use bytes::Bytes;
 use event_listener::Event;
-use log::info;
-use std::{io::ErrorKind, sync::Arc, time::Duration};
-use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt},
-	net::UnixStream,
-};
+use futures::{Stream, StreamExt};
+use log::{debug, info, warn};
+use std::{io::ErrorKind, pin::pin, sync::Arc, time::Instant};
+use tokio::{select, sync::mpsc};
 
 use crate::daemon::{
-	blconn::{self, L2CapAddr},
+	blconn::L2CapAddr,
+	capture::{Capture, Recorded},
 	packet::BatteryStatus,
 };
 
 use super::{
+	CommandReceiver, CommandRequest, DecodePolicy, DeviceCommand, DeviceHandle, PacketStatsState,
 	PodsBattery, PodsInEar, PodsState, PodsStatus,
-	blconn::Address,
-	packet::{BatteryComponent, EarDetectionStatus, ParsedPacket},
+	estimate::{Estimator, PodsEstimate},
+	packet::{BatteryComponent, ControlCommand, EarDetectionStatus, OutgoingPacket, ParsedPacket},
+	protocol::ProtocolError,
+	transport::{StreamTarget, StreamTransport, Transport},
 };
 
-async fn handle_stream(
-	mut stream: UnixStream,
+enum StreamEvent {
+	Read(std::io::Result<Option<Bytes>>),
+	Command(CommandRequest),
+}
+
+async fn handle_command(transport: &mut impl Transport, command: DeviceCommand) -> Result<()> {
+	match command {
+		DeviceCommand::SetNoiseControl(mode) => {
+			info!("setting noise control status to {:?}", mode);
+			transport
+				.write_frame(OutgoingPacket::Control(ControlCommand::NoiseControl(mode)).to_bytes())
+				.await
+				.context("failed to send noise control packet")
+		}
+	}
+}
+
+pub(super) async fn handle_stream(
+	transport: &mut impl Transport,
 	status: PodsState,
 	notify: Arc<Event>,
+	commands: &mut mpsc::Receiver<CommandRequest>,
+	policy: DecodePolicy,
+	stats: &PacketStatsState,
 ) -> Result<()> {
-	// handshake
-	stream
-		.write_all(&[
-			0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-			0x00, 0x00,
-		])
-		.await
-		.context("failed to send handshake")?;
-
-	// enable features
-	stream
-		.write_all(&[
-			0x04, 0x00, 0x04, 0x00, 0x4d, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-		])
-		.await
-		.context("failed to send enable features")?;
-
-	// enable notifications
-	stream
-		.write_all(&[0x04, 0x00, 0x04, 0x00, 0x0F, 0x00, 0xFF, 0xFF, 0xFF, 0xFF])
-		.await
-		.context("failed to send enable notifications")?;
+	for packet in [
+		OutgoingPacket::Handshake,
+		OutgoingPacket::EnableFeatures,
+		OutgoingPacket::SubscribeNotifications,
+	] {
+		transport
+			.write_frame(packet.to_bytes())
+			.await
+			.with_context(|| format!("failed to send {:?}", packet))?;
+	}
 
 	let mut last_stats: Option<PodsStatus> = None;
-	let mut buf = vec![0; 1024];
+	let (start, mut estimator) = (Instant::now(), Estimator::default());
 	loop {
-		match stream.read(&mut buf).await {
-			Ok(read) => {
-				let bytes = Bytes::copy_from_slice(&buf[..read]);
+		let read = match select! {
+			x = transport.read_frame() => StreamEvent::Read(x),
+			Some(x) = commands.recv() => StreamEvent::Command(x),
+		} {
+			StreamEvent::Read(x) => x,
+			StreamEvent::Command((command, reply)) => {
+				let ret = handle_command(transport, command).await;
+				let _ = reply.send(
+					ret.as_ref()
+						.copied()
+						.map_err(|x| ProtocolError::DeviceError {
+							message: format!("{x:?}"),
+						}),
+				);
+				ret?;
+				continue;
+			}
+		};
 
-				if bytes.remaining() == 0 {
-					break Ok(());
-				}
+		match read {
+			Ok(None) => break Ok(()),
+			Ok(Some(bytes)) => {
+				let packet = match ParsedPacket::decode(bytes.clone()) {
+					Ok(packet) => {
+						stats.lock().await.record(&packet);
+						packet
+					}
+					Err(err) if policy == DecodePolicy::Lenient => {
+						warn!("failed to decode packet {:x?}: {:?}", bytes.as_ref(), err);
+						stats.lock().await.malformed += 1;
+						ParsedPacket::Unknown(bytes)
+					}
+					Err(err) => break Err(err).context("failed to decode packet"),
+				};
 
-				if let Some(packet) =
-					ParsedPacket::decode(bytes).context("failed to decode packet")?
-				{
-					let mut lock = status.lock().await;
-					match packet {
-						ParsedPacket::Battery(batteries) => {
-							let locked = lock.battery.get_or_insert(PodsBattery {
-								case: BatteryStatus::Unknown,
-								left: BatteryStatus::Unknown,
-								right: BatteryStatus::Unknown,
-							});
-							for battery in batteries {
-								match battery.component {
-									BatteryComponent::Case => locked.case = battery.status,
-									BatteryComponent::Left => locked.left = battery.status,
-									BatteryComponent::Right => locked.right = battery.status,
-								}
+				let mut lock = status.lock().await;
+				match packet {
+					ParsedPacket::Battery(batteries) => {
+						let locked = lock.battery.get_or_insert(PodsBattery {
+							case: BatteryStatus::Unknown,
+							left: BatteryStatus::Unknown,
+							right: BatteryStatus::Unknown,
+						});
+						for battery in batteries {
+							match battery.component {
+								BatteryComponent::Case => locked.case = battery.status,
+								BatteryComponent::Left => locked.left = battery.status,
+								BatteryComponent::Right => locked.right = battery.status,
 							}
 						}
-						ParsedPacket::NoiseControl(status) => {
-							lock.noise = Some(status);
-						}
-						ParsedPacket::EarDetection { primary, secondary } => {
-							let locked = lock.ear.get_or_insert(PodsInEar {
-								primary: EarDetectionStatus::InCase,
-								secondary: EarDetectionStatus::InCase,
-							});
-							locked.primary = primary;
-							locked.secondary = secondary;
-						}
+						let battery = *locked;
+						lock.estimate = estimator.update(start.elapsed().as_secs(), &battery);
+					}
+					ParsedPacket::NoiseControl(status) => {
+						lock.noise = Some(status);
 					}
-					if last_stats.is_some_and(|x| x == *lock) {
+					ParsedPacket::EarDetection { primary, secondary } => {
+						let locked = lock.ear.get_or_insert(PodsInEar {
+							primary: EarDetectionStatus::InCase,
+							secondary: EarDetectionStatus::InCase,
+						});
+						locked.primary = primary;
+						locked.secondary = secondary;
+					}
+					ParsedPacket::Unknown(data) => {
+						debug!("skipping unknown packet {:x?}", data.as_ref());
 						continue;
-					} else {
-						last_stats.replace(*lock);
 					}
-					notify.notify(usize::MAX);
 				}
+				if last_stats.is_some_and(|x| x == *lock) {
+					continue;
+				} else {
+					last_stats.replace(*lock);
+				}
+				notify.notify(usize::MAX);
 			}
 			Err(err) => {
 				if matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::TimedOut) {
@@ -110,30 +149,100 @@ async fn handle_stream(
 	}
 }
 
-pub async fn bluetooth_setup(addr: Address) -> Result<(Device, String)> {
+/// Clears everything that is only known while the device is connected.
+pub(super) async fn set_disconnected(status: &PodsState, notify: &Event) {
+	let mut locked = status.lock().await;
+	locked.connected = false;
+	locked.ear.take();
+	locked.battery.take();
+	locked.noise.take();
+	locked.estimate = PodsEstimate::default();
+
+	notify.notify(usize::MAX);
+}
+
+/// Waits for `fut`, rejecting the commands that come in meanwhile as there is no device to run
+/// them on.
+pub(super) async fn without_device<T>(
+	commands: &mut mpsc::Receiver<CommandRequest>,
+	fut: impl Future<Output = T>,
+) -> T {
+	let mut fut = pin!(fut);
+	loop {
+		select! {
+			ret = &mut fut => break ret,
+			Some((command, reply)) = commands.recv() => {
+				warn!("ignoring command {:?}, device is not connected", command);
+				let _ = reply.send(Err(ProtocolError::DeviceNotConnected));
+			}
+		}
+	}
+}
+
+pub async fn bluetooth_setup(adapter: Option<&str>) -> Result<Adapter> {
 	let session = Session::new()
 		.await
 		.context("failed to connect to bluetoothd")?;
-	let adapter = session
-		.default_adapter()
-		.await
-		.context("failed to get default adapter")?;
-	let device = adapter
-		.device(bluer::Address::new(addr.into_inner()))
-		.context("failed to get device")?;
-
-	let name = device.adapter_name().to_string();
+	match adapter {
+		Some(name) => {
+			let adapter = session
+				.adapter(name)
+				.with_context(|| format!("failed to get adapter {name}"))?;
+			// getting the adapter doesn't check that it exists
+			adapter
+				.address()
+				.await
+				.with_context(|| format!("adapter {name} is not available"))?;
+			Ok(adapter)
+		}
+		None => session
+			.default_adapter()
+			.await
+			.context("failed to get default adapter"),
+	}
+}
 
-	Ok((device, name))
+async fn wait_for_connected(
+	events: &mut (impl Stream<Item = DeviceEvent> + Unpin),
+	connected: bool,
+) -> Result<()> {
+	while let Some(event) = events.next().await {
+		match event {
+			DeviceEvent::PropertyChanged(DeviceProperty::Connected(x)) if x == connected => {
+				return Ok(());
+			}
+			_ => {}
+		}
+	}
+	bail!("device event stream ended")
 }
 
 pub async fn bluetooth_main(
-	addr: Address,
-	status: PodsState,
-	notify: Arc<Event>,
+	handle: DeviceHandle,
+	adapter: Adapter,
 	device: Device,
+	commands: CommandReceiver,
+	policy: DecodePolicy,
+	capture: Option<Arc<Capture>>,
 ) -> Result<()> {
-	let mut was_waiting = true;
+	let DeviceHandle {
+		addr,
+		status,
+		notify,
+		stats,
+		..
+	} = handle;
+	let mut commands = commands.lock().await;
+
+	let mut events = Box::pin(
+		device
+			.events()
+			.await
+			.context("failed to subscribe to device events")?,
+	);
+
+	// clear out anything left over from before a restart
+	let mut was_waiting = false;
 	loop {
 		// so that we don't steal the device from bluetoothd making it impossible to connect for
 		// audio
@@ -144,27 +253,50 @@ pub async fn bluetooth_main(
 		{
 			was_waiting = false;
 			info!("connecting to {}", addr);
-			let stream = blconn::connect(L2CapAddr::new(addr, 0x1001))
+			let local = adapter
+				.address()
 				.await
-				.context("failed to connect to address")?;
+				.context("failed to get adapter address")?;
+			let target = StreamTarget::L2Cap {
+				local: L2CapAddr::new(local.into(), 0),
+				addr: L2CapAddr::new(addr, 0x1001),
+			};
+			let mut transport =
+				Recorded::<StreamTransport>::connect((target, addr, capture.clone()))
+					.await
+					.context("failed to connect to address")?;
 			info!("connected to device over l2cap");
+			status.lock().await.connected = true;
+			notify.notify(usize::MAX);
 
-			handle_stream(stream, status.clone(), notify.clone())
-				.await
-				.context("failed to handle device stream")?;
+			select! {
+				ret = handle_stream(
+					&mut transport,
+					status.clone(),
+					notify.clone(),
+					&mut commands,
+					policy,
+					&stats,
+				) => ret.context("failed to handle device stream")?,
+				ret = wait_for_connected(&mut events, false) => {
+					ret?;
+					info!("device disconnected, closing l2cap connection");
+				}
+			}
+			if let Err(err) = transport.close().await {
+				debug!("failed to close l2cap connection: {:?}", err);
+			}
 		}
 
 		if !was_waiting {
-			let mut locked = status.lock().await;
-			locked.ear.take();
-			locked.battery.take();
-			locked.noise.take();
-
-			notify.notify(usize::MAX);
+			set_disconnected(&status, &notify).await;
 			was_waiting = true;
 		}
 
 		info!("waiting for device to connect");
-		tokio::time::sleep(Duration::from_secs(10)).await;
+		without_device(&mut commands, wait_for_connected(&mut events, true)).await?;
 	}
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,268 @@
+//! Drives `handle_stream` end to end over an in-memory transport, with the tests playing the
+//! device.
+
+use std::{io, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use bytes::Bytes;
+use event_listener::Event;
+use tokio::{
+	sync::{Mutex, mpsc, oneshot},
+	task::JoinHandle,
+	time::timeout,
+};
+
+use super::handle_stream;
+use crate::daemon::{
+	CommandRequest, DecodePolicy, DeviceCommand, PacketStats, PacketStatsState, PodsBattery,
+	PodsInEar, PodsState, PodsStatus,
+	packet::{
+		BatteryStatus, ControlCommand, EarDetectionStatus, NoiseControlStatus, OutgoingPacket,
+	},
+	protocol::ProtocolError,
+	transport::{MemoryDevice, MemoryTransport, Transport},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const BATTERY: &[u8] = &[
+	0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x03, // header, 3 components
+	0x04, 0x01, 0x50, 0x02, 0x01, // left 80% discharging
+	0x02, 0x01, 0x4B, 0x02, 0x01, // right 75% discharging
+	0x08, 0x01, 0x28, 0x01, 0x01, // case 40% charging
+];
+const RIGHT_BATTERY: &[u8] = &[
+	0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x01, // header, 1 component
+	0x02, 0x01, 0x46, 0x02, 0x01, // right 70% discharging
+];
+const MALFORMED_BATTERY: &[u8] = &[
+	0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x01, // header, 1 component
+	0x04, 0x07, 0x50, 0x02, 0x01, // bad spacer
+];
+const NOISE_ANC: &[u8] = &[
+	0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0D, 0x02, 0x00, 0x00, 0x00,
+];
+const EAR: &[u8] = &[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x02];
+const UNKNOWN: &[u8] = &[0x04, 0x00, 0x04, 0x00, 0x2B, 0x00, 0x01, 0x02];
+
+struct Session {
+	device: MemoryDevice,
+	status: PodsState,
+	notify: Arc<Event>,
+	stats: PacketStatsState,
+	commands: mpsc::Sender<CommandRequest>,
+	task: JoinHandle<Result<()>>,
+}
+
+impl Session {
+	/// Starts `handle_stream` and checks that it sets up the connection like the daemon does.
+	async fn start(policy: DecodePolicy) -> Self {
+		let (listener, mut connections) = mpsc::unbounded_channel();
+		let mut transport = MemoryTransport::connect(listener).await.unwrap();
+		let mut device = connections.recv().await.unwrap();
+		let status = Arc::new(Mutex::new(PodsStatus::unknown()));
+		let notify = Arc::new(Event::new());
+		let stats = Arc::new(Mutex::new(PacketStats::default()));
+		let (commands, mut commands_rx) = mpsc::channel(16);
+
+		let task = tokio::spawn({
+			let (status, notify, stats) = (status.clone(), notify.clone(), stats.clone());
+			async move {
+				handle_stream(
+					&mut transport,
+					status,
+					notify,
+					&mut commands_rx,
+					policy,
+					&stats,
+				)
+				.await
+			}
+		});
+
+		for expected in [
+			OutgoingPacket::Handshake,
+			OutgoingPacket::EnableFeatures,
+			OutgoingPacket::SubscribeNotifications,
+		] {
+			assert_eq!(receive(&mut device).await, expected);
+		}
+
+		Self {
+			device,
+			status,
+			notify,
+			stats,
+			commands,
+			task,
+		}
+	}
+
+	/// Sends `frame` from the device and waits for the status to change.
+	async fn update(&self, frame: &[u8]) -> PodsStatus {
+		let listener = self.notify.listen();
+		self.send(frame);
+		timeout(TIMEOUT, listener)
+			.await
+			.expect("status was not updated");
+		*self.status.lock().await
+	}
+
+	fn send(&self, frame: &[u8]) {
+		self.device
+			.tx
+			.send(Ok(Bytes::copy_from_slice(frame)))
+			.unwrap();
+	}
+
+	async fn finish(self) -> Result<()> {
+		timeout(TIMEOUT, self.task)
+			.await
+			.expect("stream did not end")
+			.unwrap()
+	}
+}
+
+async fn receive(device: &mut MemoryDevice) -> OutgoingPacket {
+	let frame = timeout(TIMEOUT, device.rx.recv())
+		.await
+		.expect("nothing was sent to the device")
+		.expect("transport was closed");
+	OutgoingPacket::decode(frame).unwrap()
+}
+
+#[tokio::test]
+async fn battery_updates() {
+	let session = Session::start(DecodePolicy::Strict).await;
+
+	let status = session.update(BATTERY).await;
+	assert_eq!(
+		status.battery,
+		Some(PodsBattery {
+			case: BatteryStatus::Charging(40),
+			left: BatteryStatus::Discharging(80),
+			right: BatteryStatus::Discharging(75),
+		})
+	);
+
+	// components missing from a packet keep their last status
+	let status = session.update(RIGHT_BATTERY).await;
+	let battery = status.battery.unwrap();
+	assert_eq!(battery.left, BatteryStatus::Discharging(80));
+	assert_eq!(battery.right, BatteryStatus::Discharging(70));
+
+	assert_eq!(session.stats.lock().await.battery, 2);
+}
+
+#[tokio::test]
+async fn noise_control_and_ear_detection() {
+	let session = Session::start(DecodePolicy::Strict).await;
+
+	let status = session.update(NOISE_ANC).await;
+	assert_eq!(status.noise, Some(NoiseControlStatus::NoiseCancellation));
+
+	let status = session.update(EAR).await;
+	assert_eq!(
+		status.ear,
+		Some(PodsInEar {
+			primary: EarDetectionStatus::InEar,
+			secondary: EarDetectionStatus::InCase,
+		})
+	);
+}
+
+#[tokio::test]
+async fn set_noise_control() {
+	let mut session = Session::start(DecodePolicy::Strict).await;
+
+	let (tx, rx) = oneshot::channel();
+	let command = DeviceCommand::SetNoiseControl(NoiseControlStatus::Transparency);
+	session.commands.send((command, tx)).await.unwrap();
+
+	assert_eq!(
+		receive(&mut session.device).await,
+		OutgoingPacket::Control(ControlCommand::NoiseControl(
+			NoiseControlStatus::Transparency
+		))
+	);
+	assert_eq!(timeout(TIMEOUT, rx).await.unwrap().unwrap(), Ok(()));
+}
+
+#[tokio::test]
+async fn failed_command_is_reported() {
+	let mut session = Session::start(DecodePolicy::Strict).await;
+	session.device.rx.close();
+
+	let (tx, rx) = oneshot::channel();
+	let command = DeviceCommand::SetNoiseControl(NoiseControlStatus::Off);
+	session.commands.send((command, tx)).await.unwrap();
+
+	assert!(matches!(
+		timeout(TIMEOUT, rx).await.unwrap().unwrap(),
+		Err(ProtocolError::DeviceError { .. })
+	));
+	assert!(session.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn unknown_packets_are_skipped() {
+	let session = Session::start(DecodePolicy::Strict).await;
+
+	session.send(UNKNOWN);
+	session.update(NOISE_ANC).await;
+
+	let stats = *session.stats.lock().await;
+	assert_eq!(stats.unknown, 1);
+	assert_eq!(stats.noise_control, 1);
+}
+
+#[tokio::test]
+async fn lenient_policy_skips_malformed_packets() {
+	let session = Session::start(DecodePolicy::Lenient).await;
+
+	session.send(MALFORMED_BATTERY);
+	let status = session.update(BATTERY).await;
+	assert!(status.battery.is_some());
+
+	let stats = *session.stats.lock().await;
+	assert_eq!(stats.malformed, 1);
+	assert_eq!(stats.battery, 1);
+}
+
+#[tokio::test]
+async fn strict_policy_fails_on_malformed_packets() {
+	let session = Session::start(DecodePolicy::Strict).await;
+
+	session.send(MALFORMED_BATTERY);
+	assert!(session.finish().await.is_err());
+}
+
+#[tokio::test]
+async fn device_closing_ends_stream() {
+	let session = Session::start(DecodePolicy::Strict).await;
+	session.update(BATTERY).await;
+
+	let Session { device, task, .. } = session;
+	drop(device.tx);
+	timeout(TIMEOUT, task).await.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn read_errors() {
+	// the device going to sleep is not an error
+	let session = Session::start(DecodePolicy::Strict).await;
+	session
+		.device
+		.tx
+		.send(Err(io::ErrorKind::ConnectionReset.into()))
+		.unwrap();
+	session.finish().await.unwrap();
+
+	let session = Session::start(DecodePolicy::Strict).await;
+	session
+		.device
+		.tx
+		.send(Err(io::ErrorKind::PermissionDenied.into()))
+		.unwrap();
+	assert!(session.finish().await.is_err());
+}
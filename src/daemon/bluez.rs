@@ -1,14 +1,18 @@
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use event_listener::Event;
-use log::info;
+use futures::{StreamExt, future::select_all};
+use log::{info, warn};
+use tokio::select;
 use zbus::{
-	Connection, conn::Builder as ConnBuilder, fdo::ObjectManager, interface, proxy,
+	Connection,
+	conn::Builder as ConnBuilder,
+	fdo::{DBusProxy, ObjectManager},
+	interface, proxy,
 	zvariant::OwnedObjectPath,
 };
 
-use super::{PodsBattery, PodsState, blconn::Address};
+use super::{DaemonState, supervisor::ConfigError};
 
 #[proxy]
 trait BatteryProviderManager {
@@ -48,32 +52,101 @@ async fn create_conn(at: &str) -> Result<Connection> {
 		.context("failed to build")
 }
 
-fn calculate_percentage(data: Option<PodsBattery>) -> Option<u8> {
-	data.and_then(|x| match (x.left.as_percent(), x.right.as_percent()) {
-		(Some(l), Some(r)) => Some((l + r) / 2),
-		(Some(l), None) => Some(l),
-		(None, Some(r)) => Some(r),
-		(None, None) => None,
-	})
+async fn update_battery(
+	conn: &Connection,
+	iface_name: &OwnedObjectPath,
+	bluez_name: &OwnedObjectPath,
+	percent: Option<u8>,
+) -> Result<()> {
+	let iface = conn
+		.object_server()
+		.interface::<_, Battery>(iface_name)
+		.await
+		.ok();
+
+	match (iface, percent) {
+		(Some(iface), Some(percent)) => {
+			info!(
+				"updating bluez battery percentage of {} to {:?}%",
+				iface_name, percent
+			);
+			let mut iface_ref = iface.get_mut().await;
+			iface_ref.percentage = percent;
+			iface_ref
+				.percentage_changed(iface.signal_emitter())
+				.await
+				.context("failed to fire percentage changed signal")?;
+		}
+		(Some(iface), None) => {
+			info!(
+				"removing bluez battery {}, battery percentage is unavailable",
+				iface_name
+			);
+			drop(iface);
+			conn.object_server()
+				.remove::<Battery, _>(iface_name)
+				.await
+				.context("failed to remove battery from manager")?;
+		}
+		(None, Some(percent)) => {
+			info!(
+				"adding bluez battery {}, battery percentage is now available: {:?}%",
+				iface_name, percent
+			);
+			conn.object_server()
+				.at(
+					iface_name,
+					Battery {
+						device: bluez_name.clone(),
+						percentage: percent,
+					},
+				)
+				.await
+				.context("failed to add battery to manager")?;
+		}
+		(None, None) => {
+			// nothing to do
+		}
+	}
+
+	Ok(())
 }
 
-pub async fn bluez_main(
-	addr: Address,
-	status: PodsState,
-	notify: Arc<Event>,
-	name: String,
+/// Registers the objects under `prefix` as a battery provider for adapter `name`.
+async fn register_provider(
+	proxy: &BatteryProviderManagerProxy<'_>,
+	name: &str,
+	prefix: &str,
 ) -> Result<()> {
-	let dev = addr.to_string().replace(":", "_");
-	let prefix = format!("/dev/r58playz/airpodsd_{dev}");
-	let iface_name = OwnedObjectPath::try_from(format!("{prefix}/dev_{dev}"))
-		.context("interface object path is invalid")?;
-	let bluez_name = OwnedObjectPath::try_from(format!("/org/bluez/{name}/dev_{dev}"))
-		.context("bluez object path is invalid")?;
-	let conn = create_conn(&prefix)
+	info!("registering bluez battery provider on /org/bluez/{}", name);
+
+	proxy
+		.register_battery_provider(
+			OwnedObjectPath::try_from(prefix).context("failed to create battery provider path")?,
+		)
 		.await
-		.context("failed to connect to d-bus system bus")?;
+		.context("failed to register battery provider")?;
 
-	info!("registering bluez battery provider on /org/bluez/{}", name);
+	info!("registered bluez battery provider");
+	Ok(())
+}
+
+/// Exports the battery levels of every device to bluez.
+pub async fn bluez_main(state: Arc<DaemonState>, name: String) -> Result<()> {
+	let prefix = "/dev/r58playz/airpodsd";
+	let mut devices = Vec::with_capacity(state.devices.len());
+	for handle in &state.devices {
+		let dev = handle.addr.to_string().replace(":", "_");
+		let iface_name = OwnedObjectPath::try_from(format!("{prefix}/dev_{dev}"))
+			.context(ConfigError("interface object path is invalid"))?;
+		let bluez_name = OwnedObjectPath::try_from(format!("/org/bluez/{name}/dev_{dev}"))
+			.context(ConfigError("bluez object path is invalid"))?;
+		devices.push((handle, iface_name, bluez_name));
+	}
+
+	let conn = create_conn(prefix)
+		.await
+		.context("failed to connect to d-bus system bus")?;
 
 	let proxy = BatteryProviderManagerProxy::builder(&conn)
 		.interface("org.bluez.BatteryProviderManager1")
@@ -86,66 +159,52 @@ pub async fn bluez_main(
 		.await
 		.context("failed to create battery manager proxy")?;
 
-	proxy
-		.register_battery_provider(
-			OwnedObjectPath::try_from(prefix).context("failed to create battery provider path")?,
-		)
+	// subscribed before registering so that a restart of bluetoothd in between isn't missed
+	let mut owner_changes = DBusProxy::new(&conn)
 		.await
-		.context("failed to register battery provider")?;
+		.context("failed to create d-bus proxy")?
+		.receive_name_owner_changed_with_args(&[(0, "org.bluez")])
+		.await
+		.context("failed to watch org.bluez name owner")?;
 
-	info!("registered bluez battery provider");
+	register_provider(&proxy, &name, prefix).await?;
 
-	let mut last_battery_info = None;
+	let mut last_percentage = vec![None; devices.len()];
 	loop {
-		notify.listen().await;
+		let policy = *state.battery_policy.lock().await;
+
+		for ((handle, iface_name, bluez_name), last) in devices.iter().zip(&mut last_percentage) {
+			let status = *handle.status.lock().await;
+			let percent = status
+				.battery
+				.and_then(|x| policy.aggregate(&x, status.ear));
+			if last.is_some_and(|x| x == percent) {
+				continue;
+			} else {
+				last.replace(percent);
+			}
 
-		let locked = status.lock().await;
-		if last_battery_info.is_some_and(|x| x == locked.battery) {
-			continue;
-		} else {
-			last_battery_info.replace(locked.battery);
+			update_battery(&conn, iface_name, bluez_name, percent).await?;
 		}
 
-		let percent = calculate_percentage(locked.battery);
-		let iface = conn
-			.object_server()
-			.interface::<_, Battery>(&iface_name)
-			.await
-			.ok();
-
-		match (iface, percent) {
-			(Some(iface), Some(percent)) => {
-				info!("updating bluez battery percentage to {:?}%", percent);
-				let mut iface_ref = iface.get_mut().await;
-				iface_ref.percentage = percent;
-				iface_ref
-					.percentage_changed(iface.signal_emitter())
-					.await
-					.context("failed to fire percentage changed signal")?;
-			}
-			(Some(iface), None) => {
-				info!("removing bluez battery, battery percentage is unavailable");
-				drop(iface);
-				conn.object_server()
-					.remove::<Battery, _>(&iface_name)
-					.await
-					.context("failed to remove battery from manager")?;
-			}
-			(None, Some(percent)) => {
-				info!(
-					"adding bluez battery, battery percentage is now available: {:?}%",
-					percent
-				);
-				conn.object_server()
-					.at(&iface_name, Battery {
-						device: bluez_name.clone(),
-						percentage: percent,
-					})
-					.await
-					.context("failed to add battery to manager")?;
-			}
-			(None, None) => {
-				// nothing to do
+		select! {
+			_ = select_all(state.devices.iter().map(|x| x.notify.listen())) => {}
+			_ = state.settings_changed.listen() => {}
+			signal = owner_changes.next() => {
+				let signal = signal.context("org.bluez name owner stream ended")?;
+				let args = signal.args().context("failed to parse name owner change")?;
+				if args.new_owner().is_none() {
+					warn!("bluetoothd left the system bus, waiting for it to come back");
+					continue;
+				}
+
+				info!("bluetoothd restarted, registering bluez battery provider again");
+				// the batteries are added again after registering so that bluez sees them being added
+				for ((_, iface_name, bluez_name), last) in devices.iter().zip(&mut last_percentage) {
+					update_battery(&conn, iface_name, bluez_name, None).await?;
+					last.take();
+				}
+				register_provider(&proxy, &name, prefix).await?;
 			}
 		}
 	}
@@ -0,0 +1,398 @@
+use std::{
+	collections::VecDeque,
+	fmt::Write as _,
+	future::pending,
+	io,
+	path::Path,
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	fs::File,
+	io::AsyncWriteExt,
+	sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+	time::{Instant, sleep_until},
+};
+
+use super::{
+	Address, CommandReceiver, DecodePolicy, DeviceHandle,
+	bluetooth::{handle_stream, without_device},
+	supervisor::ConfigError,
+	transport::Transport,
+};
+
+/// Longest pause between two replayed frames, so that long captures replay quickly.
+const MAX_GAP: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+	/// Sent by the device.
+	Received,
+	/// Sent to the device.
+	Sent,
+}
+
+/// A raw frame, stored as one JSON line per record.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct CaptureRecord {
+	/// Milliseconds since the unix epoch.
+	pub time: u64,
+	pub address: Address,
+	pub direction: Direction,
+	/// The frame in hex.
+	pub data: String,
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+	data.iter().fold(String::new(), |mut s, x| {
+		let _ = write!(s, "{x:02x}");
+		s
+	})
+}
+
+/// Parses hex bytes, ignoring any whitespace between them.
+pub fn parse_hex(s: &str) -> Result<Bytes> {
+	let digits: String = s.split_whitespace().collect();
+	if digits.is_empty() || !digits.len().is_multiple_of(2) {
+		bail!("expected an even number of hex digits");
+	}
+	(0..digits.len())
+		.step_by(2)
+		.map(|i| {
+			u8::from_str_radix(&digits[i..i + 2], 16).with_context(|| {
+				format!("invalid hex byte {:?} at byte {}", &digits[i..i + 2], i / 2)
+			})
+		})
+		.collect::<Result<Vec<u8>>>()
+		.map(Bytes::from)
+}
+
+/// Capture file that the frames of every device are written to for `--record`.
+pub struct Capture {
+	/// Records for the task that writes them, so that recording a frame never waits.
+	tx: UnboundedSender<CaptureRecord>,
+}
+
+impl Capture {
+	pub async fn create(path: &Path) -> Result<Self> {
+		let file = File::create(path)
+			.await
+			.with_context(|| format!("failed to create capture file {}", path.display()))?;
+		let (tx, rx) = unbounded_channel();
+		tokio::spawn(write_records(file, rx));
+		Ok(Self { tx })
+	}
+
+	fn write(&self, addr: Address, direction: Direction, data: &[u8]) {
+		let record = CaptureRecord {
+			time: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |x| x.as_millis() as u64),
+			address: addr,
+			direction,
+			data: to_hex(data),
+		};
+		// the writer only goes away along with the runtime
+		let _ = self.tx.send(record);
+	}
+}
+
+async fn write_record(file: &mut File, record: &CaptureRecord) -> Result<()> {
+	let mut line = serde_json::to_vec(record).context("failed to serialize capture record")?;
+	line.push(b'\n');
+	file.write_all(&line)
+		.await
+		.context("failed to write capture record")?;
+	file.flush().await.context("failed to flush capture file")
+}
+
+async fn write_records(mut file: File, mut rx: UnboundedReceiver<CaptureRecord>) {
+	while let Some(record) = rx.recv().await {
+		if let Err(err) = write_record(&mut file, &record).await {
+			// losing the capture is no reason to lose the device
+			warn!("failed to record frame: {:?}", err);
+		}
+	}
+}
+
+/// Reads every record of a capture file.
+pub fn read(path: &Path) -> Result<Vec<CaptureRecord>> {
+	let data = std::fs::read_to_string(path)
+		.with_context(|| format!("failed to read capture file {}", path.display()))?;
+	data.lines()
+		.enumerate()
+		.filter(|(_, line)| !line.trim().is_empty())
+		.map(|(i, line)| {
+			serde_json::from_str(line)
+				.with_context(|| format!("failed to parse line {} of {}", i + 1, path.display()))
+		})
+		.collect()
+}
+
+/// Writes the frames of `inner` to `capture`, if there is one.
+pub struct Recorded<T> {
+	inner: T,
+	addr: Address,
+	capture: Option<Arc<Capture>>,
+}
+
+impl<T: Transport> Recorded<T> {
+	pub fn new(inner: T, addr: Address, capture: Option<Arc<Capture>>) -> Self {
+		Self {
+			inner,
+			addr,
+			capture,
+		}
+	}
+
+	fn record(&self, direction: Direction, data: &[u8]) {
+		if let Some(capture) = &self.capture {
+			capture.write(self.addr, direction, data);
+		}
+	}
+}
+
+impl<T: Transport> Transport for Recorded<T> {
+	type Target = (T::Target, Address, Option<Arc<Capture>>);
+
+	async fn connect((target, addr, capture): Self::Target) -> io::Result<Self> {
+		Ok(Self::new(T::connect(target).await?, addr, capture))
+	}
+
+	async fn read_frame(&mut self) -> io::Result<Option<Bytes>> {
+		let ret = self.inner.read_frame().await;
+		// nothing is awaited once the frame is read, which would lose it if the read is cancelled
+		if let Ok(Some(frame)) = &ret {
+			self.record(Direction::Received, frame);
+		}
+		ret
+	}
+
+	async fn write_frame(&mut self, frame: Bytes) -> io::Result<()> {
+		self.record(Direction::Sent, &frame);
+		self.inner.write_frame(frame).await
+	}
+
+	async fn close(&mut self) -> io::Result<()> {
+		self.inner.close().await
+	}
+}
+
+/// Plays back the frames that a device sent in a capture, and drops everything sent to it.
+pub struct ReplayTransport {
+	frames: VecDeque<(u64, Bytes)>,
+	last_time: Option<u64>,
+	/// When the next frame is due, kept across cancelled reads.
+	next_at: Option<Instant>,
+}
+
+impl ReplayTransport {
+	/// Takes the frames that `addr` sent out of `records`.
+	pub fn new(records: &[CaptureRecord], addr: Address) -> Result<Self> {
+		let frames = records
+			.iter()
+			.filter(|x| x.address == addr && x.direction == Direction::Received)
+			.map(|x| Ok((x.time, parse_hex(&x.data)?)))
+			.collect::<Result<_>>()
+			.context(ConfigError("failed to parse captured frame"))?;
+		Ok(Self::with_frames(frames))
+	}
+
+	fn with_frames(frames: VecDeque<(u64, Bytes)>) -> Self {
+		Self {
+			frames,
+			last_time: None,
+			next_at: None,
+		}
+	}
+}
+
+impl Transport for ReplayTransport {
+	/// The frames to play back along with when they were received, as there is nothing to
+	/// connect to.
+	type Target = VecDeque<(u64, Bytes)>;
+
+	async fn connect(frames: VecDeque<(u64, Bytes)>) -> io::Result<Self> {
+		Ok(Self::with_frames(frames))
+	}
+
+	async fn read_frame(&mut self) -> io::Result<Option<Bytes>> {
+		let Some(&(time, _)) = self.frames.front() else {
+			return Ok(None);
+		};
+		let gap = self.last_time.map_or(0, |x| time.saturating_sub(x));
+		let next_at = *self
+			.next_at
+			.get_or_insert_with(|| Instant::now() + Duration::from_millis(gap).min(MAX_GAP));
+		sleep_until(next_at).await;
+
+		self.next_at = None;
+		self.last_time = Some(time);
+		Ok(self.frames.pop_front().map(|x| x.1))
+	}
+
+	async fn write_frame(&mut self, frame: Bytes) -> io::Result<()> {
+		debug!("dropping frame for replayed device: {:x?}", frame.as_ref());
+		Ok(())
+	}
+
+	async fn close(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Feeds the frames that `handle`'s device sent in a capture into the daemon once, and keeps
+/// the last status around afterwards.
+pub async fn replay_main(
+	handle: DeviceHandle,
+	commands: CommandReceiver,
+	policy: DecodePolicy,
+	records: Arc<Vec<CaptureRecord>>,
+) -> Result<()> {
+	let DeviceHandle {
+		addr,
+		status,
+		notify,
+		stats,
+		..
+	} = handle;
+	let mut commands = commands.lock().await;
+
+	let mut transport = ReplayTransport::new(&records, addr)?;
+	info!("replaying {} frames for {}", transport.frames.len(), addr);
+	status.lock().await.connected = true;
+	notify.notify(usize::MAX);
+
+	// the errors are what a replay is looking for, so they don't restart it
+	match handle_stream(
+		&mut transport,
+		status.clone(),
+		notify.clone(),
+		&mut commands,
+		policy,
+		&stats,
+	)
+	.await
+	{
+		Ok(()) => info!("replay for {} finished", addr),
+		Err(err) => warn!("replay for {} failed: {:?}", addr, err),
+	}
+	status.lock().await.connected = false;
+	notify.notify(usize::MAX);
+
+	without_device(&mut commands, pending()).await
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::Arc, time::Duration};
+
+	use bytes::Bytes;
+	use tokio::{
+		select,
+		sync::mpsc::unbounded_channel,
+		time::{Instant, sleep},
+	};
+
+	use super::{
+		Capture, CaptureRecord, Direction, MAX_GAP, Recorded, ReplayTransport, parse_hex, read,
+		to_hex,
+	};
+	use crate::daemon::{
+		Address,
+		transport::{MemoryTransport, Transport},
+	};
+
+	#[test]
+	fn hex() {
+		let data = [0x04, 0x00, 0xAB, 0xFF];
+		assert_eq!(to_hex(&data), "0400abff");
+		assert_eq!(parse_hex("04 00abFF").unwrap().as_ref(), data);
+		assert!(parse_hex("040").is_err());
+		assert!(parse_hex("04zz").is_err());
+		assert!(parse_hex("").is_err());
+	}
+
+	#[tokio::test]
+	async fn recorded_reads_are_cancel_safe() {
+		let addr: Address = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+		let path =
+			std::env::temp_dir().join(format!("airpodsd-capture-{}.jsonl", std::process::id()));
+		let capture = Arc::new(Capture::create(&path).await.unwrap());
+		let (transport, device) = MemoryTransport::pair();
+		let mut transport = Recorded::new(transport, addr, Some(capture));
+
+		// every read races a command that is ready right away, like in handle_stream
+		let (commands_tx, mut commands) = unbounded_channel();
+		for i in 0..20u8 {
+			device.tx.send(Ok(Bytes::from(vec![i]))).unwrap();
+			commands_tx.send(()).unwrap();
+		}
+		drop(device);
+
+		let mut frames = Vec::new();
+		loop {
+			select! {
+				frame = transport.read_frame() => match frame.unwrap() {
+					Some(frame) => frames.push(frame[0]),
+					None => break,
+				},
+				Some(()) = commands.recv() => {}
+			}
+		}
+		assert_eq!(frames, (0..20).collect::<Vec<u8>>());
+
+		let mut records = Vec::new();
+		for _ in 0..100 {
+			records = read(&path).unwrap();
+			if records.len() == frames.len() {
+				break;
+			}
+			sleep(Duration::from_millis(10)).await;
+		}
+		std::fs::remove_file(&path).unwrap();
+		let data: Vec<String> = (0..20u8).map(|x| to_hex(&[x])).collect();
+		assert_eq!(
+			records.into_iter().map(|x| x.data).collect::<Vec<_>>(),
+			data
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn replay_only_plays_received_frames() {
+		let addr: Address = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+		let other: Address = "11:22:33:44:55:66".parse().unwrap();
+		let record = |time, address, direction, data: &str| CaptureRecord {
+			time,
+			address,
+			direction,
+			data: data.to_string(),
+		};
+		let records = [
+			record(0, addr, Direction::Sent, "000004000100"),
+			record(10, addr, Direction::Received, "0400040006000002"),
+			record(20, other, Direction::Received, "0400040006000101"),
+			record(5000, addr, Direction::Received, "0400040006000100"),
+		];
+
+		let mut transport = ReplayTransport::new(&records, addr).unwrap();
+		let start = Instant::now();
+		assert_eq!(
+			transport.read_frame().await.unwrap().unwrap().as_ref(),
+			[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x02]
+		);
+		assert_eq!(
+			transport.read_frame().await.unwrap().unwrap().as_ref(),
+			[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x01, 0x00]
+		);
+		// the five second gap is shortened
+		assert_eq!(start.elapsed(), MAX_GAP);
+		assert!(transport.read_frame().await.unwrap().is_none());
+	}
+}
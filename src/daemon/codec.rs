@@ -0,0 +1,179 @@
+use bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::Decoder;
+
+const HEADERS: [[u8; 4]; 2] = [[0x04, 0x00, 0x04, 0x00], [0x01, 0x00, 0x04, 0x00]];
+/// Case, left and right, so a larger count can only come from a corrupt packet.
+const MAX_BATTERY_COMPONENTS: u8 = 3;
+
+enum FrameLength {
+	Known(usize),
+	Incomplete,
+	Unknown,
+}
+
+fn frame_length(data: &[u8]) -> FrameLength {
+	if data.len() < 6 {
+		return FrameLength::Incomplete;
+	}
+
+	if data[..4] != HEADERS[0] {
+		return FrameLength::Unknown;
+	}
+
+	match data[4..6] {
+		// battery, one count byte followed by 5 bytes per component
+		[0x04, 0x00] => match data.get(6) {
+			// resync instead of waiting for a length that a corrupt count byte made up
+			Some(&count) if count > MAX_BATTERY_COMPONENTS => FrameLength::Unknown,
+			Some(&count) => FrameLength::Known(7 + count as usize * 5),
+			None => FrameLength::Incomplete,
+		},
+		// ear detection, primary and secondary status
+		[0x06, 0x00] => FrameLength::Known(8),
+		// control command, identifier followed by 4 bytes of value
+		[0x09, 0x00] => FrameLength::Known(11),
+		_ => FrameLength::Unknown,
+	}
+}
+
+fn is_header_prefix(data: &[u8]) -> bool {
+	HEADERS
+		.iter()
+		.any(|x| x.starts_with(&data[..data.len().min(4)]))
+}
+
+fn next_header(data: &[u8]) -> Option<usize> {
+	// a battery header followed by its own opcode looks like another header two bytes in
+	let start = if HEADERS.iter().any(|x| data.starts_with(x)) {
+		4
+	} else {
+		1
+	};
+	data.windows(4)
+		.skip(start)
+		.position(|x| HEADERS.iter().any(|header| x == header))
+		.map(|x| x + start)
+}
+
+/// Splits the L2CAP byte stream back into AAP packets.
+///
+/// AAP has no length field, so the length of known packet types is derived from their layout
+/// and the decoder waits until the whole packet has been read. Packets of unknown types extend
+/// to the next packet header in the buffer, or to the end of the buffer if there is none.
+pub struct AapCodec;
+
+impl Decoder for AapCodec {
+	type Item = Bytes;
+	type Error = std::io::Error;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		if src.is_empty() {
+			return Ok(None);
+		}
+
+		let len = match frame_length(src) {
+			FrameLength::Known(len) if src.len() >= len => len,
+			FrameLength::Known(len) => {
+				src.reserve(len - src.len());
+				return Ok(None);
+			}
+			FrameLength::Incomplete if is_header_prefix(src) => return Ok(None),
+			FrameLength::Incomplete | FrameLength::Unknown => next_header(src).unwrap_or(src.len()),
+		};
+
+		Ok(Some(src.split_to(len).freeze()))
+	}
+
+	fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		match self.decode(buf)? {
+			Some(frame) => Ok(Some(frame)),
+			// hand out whatever is left so that a truncated packet still gets logged
+			None if buf.has_remaining() => Ok(Some(buf.split().freeze())),
+			None => Ok(None),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bytes::BytesMut;
+	use tokio_util::codec::Decoder;
+
+	use super::AapCodec;
+
+	const BATTERY: [u8; 22] = [
+		0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x03, 0x02, 0x01, 0x64, 0x02, 0x01, 0x04, 0x01, 0x63,
+		0x01, 0x01, 0x08, 0x01, 0x11, 0x02, 0x01,
+	];
+	const EAR: [u8; 8] = [0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x01];
+
+	fn decode_all(chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+		let mut codec = AapCodec;
+		let mut buf = BytesMut::new();
+		let mut frames = Vec::new();
+		for chunk in chunks {
+			buf.extend_from_slice(chunk);
+			while let Some(frame) = codec.decode(&mut buf).unwrap() {
+				frames.push(frame.to_vec());
+			}
+		}
+		while let Some(frame) = codec.decode_eof(&mut buf).unwrap() {
+			frames.push(frame.to_vec());
+		}
+		frames
+	}
+
+	#[test]
+	fn whole_packets() {
+		assert_eq!(
+			decode_all(&[&BATTERY, &EAR]),
+			vec![BATTERY.to_vec(), EAR.to_vec()]
+		);
+	}
+
+	#[test]
+	fn coalesced_packets() {
+		let coalesced = [BATTERY.as_slice(), &EAR].concat();
+		assert_eq!(
+			decode_all(&[&coalesced]),
+			vec![BATTERY.to_vec(), EAR.to_vec()]
+		);
+	}
+
+	#[test]
+	fn split_packets() {
+		for split in 1..BATTERY.len() {
+			let (a, b) = BATTERY.split_at(split);
+			assert_eq!(
+				decode_all(&[a, b, &EAR]),
+				vec![BATTERY.to_vec(), EAR.to_vec()]
+			);
+		}
+	}
+
+	#[test]
+	fn unknown_packets_resync() {
+		let unknown = [0x04, 0x00, 0x04, 0x00, 0x2B, 0x00, 0x01, 0x02];
+		let garbage = [0xAA, 0xBB];
+		let stream = [unknown.as_slice(), &EAR, &garbage, &EAR].concat();
+		assert_eq!(
+			decode_all(&[&stream]),
+			vec![
+				unknown.to_vec(),
+				EAR.to_vec(),
+				garbage.to_vec(),
+				EAR.to_vec()
+			]
+		);
+	}
+
+	#[test]
+	fn corrupt_battery_count_resyncs() {
+		let corrupt = [0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0xFF, 0x02, 0x01];
+		let stream = [corrupt.as_slice(), &EAR, &BATTERY].concat();
+		assert_eq!(
+			decode_all(&[&stream]),
+			vec![corrupt.to_vec(), EAR.to_vec(), BATTERY.to_vec()]
+		);
+	}
+}
@@ -0,0 +1,79 @@
+use std::{
+	env,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{
+	Address, DecodePolicy, battery::BatteryPolicy, dbus::DbusBus, hooks::HooksConfig,
+	mpris::EarDetectionConfig, notifications::NotificationsConfig,
+};
+
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+	env::var_os(var)
+		.filter(|x| !x.is_empty())
+		.map(PathBuf::from)
+		.or_else(|| env::var_os("HOME").map(|x| Path::new(&x).join(fallback)))
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+	xdg_dir("XDG_CONFIG_HOME", ".config").map(|x| x.join("airpodsd"))
+}
+
+pub fn state_dir() -> Option<PathBuf> {
+	xdg_dir("XDG_STATE_HOME", ".local/state").map(|x| x.join("airpodsd"))
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+	pub devices: Vec<Address>,
+	/// Also manage every paired device that speaks AAP.
+	pub all_paired: bool,
+	/// Name of the adapter to use instead of the default one, such as `hci1`.
+	pub adapter: Option<String>,
+	pub decode_policy: DecodePolicy,
+	pub battery_policy: BatteryPolicy,
+	/// Bus to publish the `dev.r58playz.airpodsd.Device1` service on.
+	pub dbus_bus: DbusBus,
+	/// What to do with media players as buds are taken out of the ears.
+	pub ear_detection: EarDetectionConfig,
+	/// Executables to run when the status of a device changes.
+	pub hooks: HooksConfig,
+	pub notifications: NotificationsConfig,
+	/// Connect to devices played by `airpodsd simulate` instead of real ones, only set by
+	/// `--simulate`.
+	#[serde(skip)]
+	pub simulate: bool,
+	/// File to write every frame sent to or received from the devices to, only set by
+	/// `--record`.
+	#[serde(skip)]
+	pub record: Option<PathBuf>,
+	/// Capture file to feed into the daemon instead of connecting to the devices, only set by
+	/// `--replay`.
+	#[serde(skip)]
+	pub replay: Option<PathBuf>,
+}
+
+impl Config {
+	pub fn read(path: &Path) -> Result<Self> {
+		let data = std::fs::read_to_string(path)
+			.with_context(|| format!("failed to read config file {}", path.display()))?;
+		toml::from_str(&data)
+			.with_context(|| format!("failed to parse config file {}", path.display()))
+	}
+
+	/// Reads the config file at `path`, or `$XDG_CONFIG_HOME/airpodsd/config.toml` if it exists.
+	pub fn load(path: Option<&Path>) -> Result<Self> {
+		if let Some(path) = path {
+			return Self::read(path);
+		}
+
+		match config_dir().map(|x| x.join("config.toml")) {
+			Some(path) if path.exists() => Self::read(&path),
+			_ => Ok(Self::default()),
+		}
+	}
+}
@@ -0,0 +1,443 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use futures::future::select_all;
+use log::info;
+use serde::{Deserialize, Serialize};
+use zbus::{
+	Connection, conn::Builder as ConnBuilder, fdo, fdo::ObjectManager, interface,
+	zvariant::OwnedObjectPath,
+};
+
+use super::{
+	DaemonState, DeviceCommand, DeviceHandle, PodsStatus,
+	estimate::Estimate,
+	packet::{BatteryStatus, EarDetectionStatus, NoiseControlStatus},
+	supervisor::ConfigError,
+};
+
+const SERVICE_NAME: &str = "dev.r58playz.airpodsd";
+const PREFIX: &str = "/dev/r58playz/airpodsd";
+
+/// Which bus the `dev.r58playz.airpodsd.Device1` service is published on.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DbusBus {
+	#[default]
+	Session,
+	System,
+	/// Don't publish the service.
+	None,
+}
+
+fn level(status: Option<BatteryStatus>) -> i32 {
+	status.and_then(|x| x.as_percent()).map_or(-1, i32::from)
+}
+
+fn charging(status: Option<BatteryStatus>) -> bool {
+	status.is_some_and(|x| x.is_charging())
+}
+
+/// Seconds until the first bud runs out, or 0 when unknown like upower does.
+fn time_to_empty(status: &PodsStatus) -> i64 {
+	[status.estimate.left, status.estimate.right]
+		.iter()
+		.filter_map(|x| match x {
+			Some(Estimate::TimeToEmpty(x)) => Some(*x as i64),
+			_ => None,
+		})
+		.min()
+		.unwrap_or(0)
+}
+
+/// Seconds until both buds are charged, or 0 when unknown.
+fn time_to_full(status: &PodsStatus) -> i64 {
+	[status.estimate.left, status.estimate.right]
+		.iter()
+		.filter_map(|x| match x {
+			Some(Estimate::TimeToFull(x)) => Some(*x as i64),
+			_ => None,
+		})
+		.max()
+		.unwrap_or(0)
+}
+
+fn noise_mode(status: Option<NoiseControlStatus>) -> &'static str {
+	match status {
+		Some(NoiseControlStatus::Off) => "off",
+		Some(NoiseControlStatus::NoiseCancellation) => "anc",
+		Some(NoiseControlStatus::Transparency) => "transparency",
+		Some(NoiseControlStatus::AdaptiveTransparency) => "adaptive",
+		None => "",
+	}
+}
+
+fn parse_noise_mode(mode: &str) -> Option<NoiseControlStatus> {
+	Some(match mode {
+		"off" => NoiseControlStatus::Off,
+		"anc" => NoiseControlStatus::NoiseCancellation,
+		"transparency" => NoiseControlStatus::Transparency,
+		"adaptive" => NoiseControlStatus::AdaptiveTransparency,
+		_ => return None,
+	})
+}
+
+fn ear_state(status: Option<EarDetectionStatus>) -> &'static str {
+	match status {
+		Some(EarDetectionStatus::InEar) => "in-ear",
+		Some(EarDetectionStatus::OutOfEar) => "out-of-ear",
+		Some(EarDetectionStatus::InCase) => "in-case",
+		None => "",
+	}
+}
+
+/// Battery levels are -1 and modes and ear states are empty when they are unknown.
+struct Device {
+	handle: DeviceHandle,
+	status: PodsStatus,
+}
+
+#[interface(name = "dev.r58playz.airpodsd.Device1")]
+impl Device {
+	#[zbus(property)]
+	async fn address(&self) -> String {
+		self.handle.addr.to_string()
+	}
+
+	#[zbus(property)]
+	async fn connected(&self) -> bool {
+		self.status.connected
+	}
+
+	#[zbus(property)]
+	async fn case_battery(&self) -> i32 {
+		level(self.status.battery.map(|x| x.case))
+	}
+
+	#[zbus(property)]
+	async fn left_battery(&self) -> i32 {
+		level(self.status.battery.map(|x| x.left))
+	}
+
+	#[zbus(property)]
+	async fn right_battery(&self) -> i32 {
+		level(self.status.battery.map(|x| x.right))
+	}
+
+	#[zbus(property)]
+	async fn case_charging(&self) -> bool {
+		charging(self.status.battery.map(|x| x.case))
+	}
+
+	#[zbus(property)]
+	async fn left_charging(&self) -> bool {
+		charging(self.status.battery.map(|x| x.left))
+	}
+
+	#[zbus(property)]
+	async fn right_charging(&self) -> bool {
+		charging(self.status.battery.map(|x| x.right))
+	}
+
+	#[zbus(property)]
+	async fn time_to_empty(&self) -> i64 {
+		time_to_empty(&self.status)
+	}
+
+	#[zbus(property)]
+	async fn time_to_full(&self) -> i64 {
+		time_to_full(&self.status)
+	}
+
+	#[zbus(property)]
+	async fn noise_mode(&self) -> &str {
+		noise_mode(self.status.noise)
+	}
+
+	#[zbus(property)]
+	async fn primary_ear(&self) -> &str {
+		ear_state(self.status.ear.map(|x| x.primary))
+	}
+
+	#[zbus(property)]
+	async fn secondary_ear(&self) -> &str {
+		ear_state(self.status.ear.map(|x| x.secondary))
+	}
+
+	/// Sets the noise control mode to one of `off`, `anc`, `transparency` or `adaptive`.
+	async fn set_noise_control(&self, mode: &str) -> fdo::Result<()> {
+		let mode = parse_noise_mode(mode)
+			.ok_or_else(|| fdo::Error::InvalidArgs(format!("invalid noise mode: {mode}")))?;
+		self.handle
+			.run(DeviceCommand::SetNoiseControl(mode))
+			.await
+			.map_err(|x| fdo::Error::Failed(x.to_string()))
+	}
+}
+
+async fn create_conn(builder: ConnBuilder<'_>) -> Result<Connection> {
+	builder
+		.name(SERVICE_NAME)
+		.context("failed to set service name")?
+		.serve_at(PREFIX, ObjectManager)
+		.context("failed to serve objmanager")?
+		.build()
+		.await
+		.map_err(|err| match err {
+			// restarting won't help while the other instance is running
+			zbus::Error::NameTaken => anyhow!(err).context(ConfigError(
+				"another airpodsd already owns the name, manage every device from one daemon or pass --dbus-bus none",
+			)),
+			err => anyhow!(err).context("failed to build"),
+		})
+}
+
+async fn update_device(
+	conn: &Connection,
+	path: &OwnedObjectPath,
+	status: PodsStatus,
+) -> Result<()> {
+	let iface = conn
+		.object_server()
+		.interface::<_, Device>(path)
+		.await
+		.context("failed to get device interface")?;
+	let mut iface_ref = iface.get_mut().await;
+	let old = iface_ref.status;
+	if old == status {
+		return Ok(());
+	}
+	iface_ref.status = status;
+
+	let emitter = iface.signal_emitter();
+	if old.connected != status.connected {
+		iface_ref.connected_changed(emitter).await?;
+	}
+	if old.battery != status.battery {
+		iface_ref.case_battery_changed(emitter).await?;
+		iface_ref.left_battery_changed(emitter).await?;
+		iface_ref.right_battery_changed(emitter).await?;
+		iface_ref.case_charging_changed(emitter).await?;
+		iface_ref.left_charging_changed(emitter).await?;
+		iface_ref.right_charging_changed(emitter).await?;
+	}
+	if time_to_empty(&old) != time_to_empty(&status) {
+		iface_ref.time_to_empty_changed(emitter).await?;
+	}
+	if time_to_full(&old) != time_to_full(&status) {
+		iface_ref.time_to_full_changed(emitter).await?;
+	}
+	if old.noise != status.noise {
+		iface_ref.noise_mode_changed(emitter).await?;
+	}
+	if old.ear != status.ear {
+		iface_ref.primary_ear_changed(emitter).await?;
+		iface_ref.secondary_ear_changed(emitter).await?;
+	}
+
+	Ok(())
+}
+
+/// Publishes the status of every device as `dev.r58playz.airpodsd.Device1` objects.
+pub async fn dbus_main(state: Arc<DaemonState>, bus: DbusBus) -> Result<()> {
+	let builder = match bus {
+		DbusBus::System => ConnBuilder::system(),
+		_ => ConnBuilder::session(),
+	}
+	.context("failed to create builder")?;
+	let conn = create_conn(builder)
+		.await
+		.with_context(|| format!("failed to publish {SERVICE_NAME} on the {bus:?} bus"))?;
+	info!("published {} on the {:?} bus", SERVICE_NAME, bus);
+
+	serve(&conn, &state).await
+}
+
+async fn serve(conn: &Connection, state: &DaemonState) -> Result<()> {
+	let mut paths = Vec::with_capacity(state.devices.len());
+	for handle in &state.devices {
+		let dev = handle.addr.to_string().replace(":", "_");
+		paths.push(
+			OwnedObjectPath::try_from(format!("{PREFIX}/dev_{dev}"))
+				.context(ConfigError("device object path is invalid"))?,
+		);
+	}
+
+	for (handle, path) in state.devices.iter().zip(&paths) {
+		let status = *handle.status.lock().await;
+		conn.object_server()
+			.at(
+				path,
+				Device {
+					handle: handle.clone(),
+					status,
+				},
+			)
+			.await
+			.context("failed to add device to object server")?;
+	}
+
+	loop {
+		select_all(state.devices.iter().map(|x| x.notify.listen())).await;
+
+		for (handle, path) in state.devices.iter().zip(&paths) {
+			let status = *handle.status.lock().await;
+			update_device(conn, path, status).await?;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::Arc, time::Duration};
+
+	use event_listener::Event;
+	use futures::StreamExt;
+	use tokio::{sync::Mutex, time::timeout};
+	use zbus::{
+		Connection, conn::Builder as ConnBuilder, fdo::PropertiesProxy, names::InterfaceName,
+		zvariant::OwnedValue,
+	};
+
+	use super::{SERVICE_NAME, create_conn, serve};
+	use crate::daemon::{
+		Address, DaemonState, DeviceCommand, PodsBattery,
+		battery::BatteryPolicy,
+		device_handles,
+		packet::{BatteryStatus, NoiseControlStatus},
+		tests::private_bus,
+	};
+
+	const PATH: &str = "/dev/r58playz/airpodsd/dev_AA_BB_CC_DD_EE_FF";
+	const INTERFACE: &str = "dev.r58playz.airpodsd.Device1";
+
+	async fn get(props: &PropertiesProxy<'_>, name: &str) -> OwnedValue {
+		props
+			.get(InterfaceName::from_static_str(INTERFACE).unwrap(), name)
+			.await
+			.unwrap()
+	}
+
+	async fn set_noise_control(client: &Connection, mode: &str) -> zbus::Result<()> {
+		client
+			.call_method(
+				Some(SERVICE_NAME),
+				PATH,
+				Some(INTERFACE),
+				"SetNoiseControl",
+				&(mode,),
+			)
+			.await
+			.map(drop)
+	}
+
+	#[tokio::test]
+	async fn publishes_devices() {
+		let (_daemon, address) = private_bus().await;
+		let addr: Address = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+		let (devices, mut receivers) = device_handles(vec![addr]).unwrap();
+		let handle = devices[0].clone();
+		handle.status.lock().await.battery = Some(PodsBattery {
+			case: BatteryStatus::Charging(30),
+			left: BatteryStatus::Discharging(80),
+			right: BatteryStatus::Unknown,
+		});
+		let state = Arc::new(DaemonState {
+			devices,
+			health: Default::default(),
+			battery_policy: Mutex::new(BatteryPolicy::default()),
+			settings_changed: Event::new(),
+		});
+
+		let conn = create_conn(ConnBuilder::address(address.as_str()).unwrap())
+			.await
+			.unwrap();
+		let task = tokio::spawn(async move { serve(&conn, &state).await });
+
+		let client = ConnBuilder::address(address.as_str())
+			.unwrap()
+			.build()
+			.await
+			.unwrap();
+		let props = PropertiesProxy::builder(&client)
+			.destination(SERVICE_NAME)
+			.unwrap()
+			.path(PATH)
+			.unwrap()
+			.build()
+			.await
+			.unwrap();
+		// the objects are added after the name is taken
+		let mut changes = props.receive_properties_changed().await.unwrap();
+		timeout(Duration::from_secs(5), async {
+			while props
+				.get_all(InterfaceName::from_static_str(INTERFACE).unwrap())
+				.await
+				.is_err()
+			{
+				tokio::task::yield_now().await;
+			}
+		})
+		.await
+		.unwrap();
+
+		assert_eq!(i32::try_from(get(&props, "CaseBattery").await).unwrap(), 30);
+		assert_eq!(i32::try_from(get(&props, "LeftBattery").await).unwrap(), 80);
+		assert_eq!(
+			i32::try_from(get(&props, "RightBattery").await).unwrap(),
+			-1
+		);
+		assert!(bool::try_from(get(&props, "CaseCharging").await).unwrap());
+		assert!(!bool::try_from(get(&props, "LeftCharging").await).unwrap());
+
+		handle.status.lock().await.battery = Some(PodsBattery {
+			case: BatteryStatus::Charging(30),
+			left: BatteryStatus::Discharging(80),
+			right: BatteryStatus::Discharging(55),
+		});
+		handle.notify.notify(usize::MAX);
+		let right = timeout(Duration::from_secs(5), async {
+			loop {
+				let signal = changes.next().await.unwrap();
+				let args = signal.args().unwrap();
+				if let Some(x) = args.changed_properties().get("RightBattery") {
+					break i32::try_from(x).unwrap();
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(right, 55);
+
+		let commands = receivers.remove(0);
+		let responder = tokio::spawn(async move {
+			let (command, tx) = commands.lock().await.recv().await.unwrap();
+			tx.send(Ok(())).unwrap();
+			command
+		});
+		assert!(set_noise_control(&client, "loud").await.is_err());
+		set_noise_control(&client, "anc").await.unwrap();
+		assert_eq!(
+			responder.await.unwrap(),
+			DeviceCommand::SetNoiseControl(NoiseControlStatus::NoiseCancellation)
+		);
+
+		task.abort();
+	}
+
+	#[tokio::test]
+	async fn second_instance_is_a_config_error() {
+		let (_daemon, address) = private_bus().await;
+		let _first = create_conn(ConnBuilder::address(address.as_str()).unwrap())
+			.await
+			.unwrap();
+		let err = create_conn(ConnBuilder::address(address.as_str()).unwrap())
+			.await
+			.unwrap_err();
+		assert!(
+			err.downcast_ref::<crate::daemon::supervisor::ConfigError>()
+				.is_some()
+		);
+	}
+}
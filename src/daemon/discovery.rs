@@ -0,0 +1,82 @@
+use anyhow::{Context, Result, bail};
+use bluer::{Adapter, Device, Uuid};
+use log::debug;
+
+use super::{Address, bluetooth::bluetooth_setup};
+
+/// Service UUID that AirPods and Beats devices advertise for AAP.
+const AAP_UUID: Uuid = Uuid::from_u128(0x74ec2172_0bad_4d01_8f77_997b2be0722a);
+/// Service UUID of the A2DP audio sink profile.
+const AUDIO_SINK_UUID: Uuid = Uuid::from_u128(0x0000110b_0000_1000_8000_00805f9b34fb);
+/// Bluetooth SIG company identifier of Apple.
+const APPLE_VENDOR_ID: u32 = 0x004C;
+
+async fn is_aap_device(device: &Device) -> Result<bool> {
+	if !device
+		.is_paired()
+		.await
+		.context("failed to get paired status")?
+	{
+		return Ok(false);
+	}
+
+	let uuids = device
+		.uuids()
+		.await
+		.context("failed to get uuids")?
+		.unwrap_or_default();
+	let apple = device
+		.modalias()
+		.await
+		.context("failed to get modalias")?
+		.is_some_and(|x| x.vendor == APPLE_VENDOR_ID);
+
+	Ok(uuids.contains(&AAP_UUID) || (apple && uuids.contains(&AUDIO_SINK_UUID)))
+}
+
+/// Lists the paired devices on `adapter` that speak AAP.
+pub async fn discover(adapter: &Adapter) -> Result<Vec<Address>> {
+	let mut found = Vec::new();
+
+	for addr in adapter
+		.device_addresses()
+		.await
+		.context("failed to list devices")?
+	{
+		let device = adapter.device(addr).context("failed to get device")?;
+		if is_aap_device(&device)
+			.await
+			.with_context(|| format!("failed to inspect device {addr}"))?
+		{
+			debug!("discovered AAP device {}", addr);
+			found.push(Address::from(addr));
+		}
+	}
+
+	found.sort();
+	Ok(found)
+}
+
+/// Finds the only paired device on `adapter` that speaks AAP.
+pub async fn discover_one(adapter: &Adapter) -> Result<Address> {
+	match discover(adapter).await?.as_slice() {
+		[addr] => Ok(*addr),
+		[] => bail!("no paired AirPods found, pass a MAC address"),
+		found => bail!(
+			"found multiple paired AirPods ({}), pass a MAC address",
+			found
+				.iter()
+				.map(|x| x.to_string())
+				.collect::<Vec<_>>()
+				.join(", ")
+		),
+	}
+}
+
+/// Finds the only paired device that speaks AAP on `adapter`, or the default adapter.
+pub async fn find_device(adapter: Option<&str>) -> Result<Address> {
+	let adapter = bluetooth_setup(adapter)
+		.await
+		.context("failed to set up bluetooth")?;
+	discover_one(&adapter).await
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use super::{PodsBattery, packet::BatteryStatus};
+
+/// How far back level changes are used for the rate.
+const WINDOW: u64 = 2 * 60 * 60;
+
+/// Remaining time of a component in seconds, as of its last battery update.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Estimate {
+	TimeToEmpty(u64),
+	TimeToFull(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PodsEstimate {
+	pub case: Option<Estimate>,
+	pub left: Option<Estimate>,
+	pub right: Option<Estimate>,
+}
+
+/// Estimates the remaining time of a single component from the times its level changed.
+///
+/// The levels are reported in steps of 5%, so the rate is only measured between two level
+/// changes: the first level that is seen may have been reached at any point before.
+#[derive(Debug, Default)]
+struct Tracker {
+	charging: bool,
+	level: Option<u8>,
+	/// Times and levels of the level changes in the current direction.
+	changes: VecDeque<(u64, u8)>,
+}
+
+impl Tracker {
+	fn reset(&mut self) {
+		self.level = None;
+		self.changes.clear();
+	}
+
+	fn update(&mut self, time: u64, status: BatteryStatus) -> Option<Estimate> {
+		let (level, charging) = match status {
+			BatteryStatus::Charging(x) => (x, true),
+			BatteryStatus::Discharging(x) => (x, false),
+			BatteryStatus::Unknown | BatteryStatus::Disconnected => {
+				self.reset();
+				return None;
+			}
+		};
+		if charging != self.charging {
+			self.charging = charging;
+			self.reset();
+		}
+
+		match self.level {
+			Some(last) if last == level => {}
+			// going the wrong way, such as a bud that recovers a bit after resting
+			Some(last) if (level > last) != charging => {
+				self.changes.clear();
+				self.level = Some(level);
+			}
+			Some(_) => {
+				self.changes.push_back((time, level));
+				self.level = Some(level);
+			}
+			None => self.level = Some(level),
+		}
+		while self.changes.len() > 2
+			&& self
+				.changes
+				.front()
+				.is_some_and(|(x, _)| *x + WINDOW < time)
+		{
+			self.changes.pop_front();
+		}
+
+		if charging && level >= 100 {
+			return Some(Estimate::TimeToFull(0));
+		}
+
+		let (&(first_time, first_level), &(last_time, last_level)) =
+			(self.changes.front()?, self.changes.back()?);
+		let (elapsed, delta) = (last_time - first_time, first_level.abs_diff(last_level));
+		if elapsed == 0 || delta == 0 {
+			return None;
+		}
+
+		let remaining = if charging { 100 - level } else { level };
+		let secs = remaining as u64 * elapsed / delta as u64;
+		Some(if charging {
+			Estimate::TimeToFull(secs)
+		} else {
+			Estimate::TimeToEmpty(secs)
+		})
+	}
+}
+
+/// Estimates the remaining time of the case and both buds.
+#[derive(Debug, Default)]
+pub struct Estimator {
+	case: Tracker,
+	left: Tracker,
+	right: Tracker,
+}
+
+impl Estimator {
+	/// Takes a new battery update at `time` in seconds.
+	pub fn update(&mut self, time: u64, battery: &PodsBattery) -> PodsEstimate {
+		PodsEstimate {
+			case: self.case.update(time, battery.case),
+			left: self.left.update(time, battery.left),
+			right: self.right.update(time, battery.right),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Estimate, Tracker};
+	use crate::daemon::packet::BatteryStatus::*;
+
+	#[test]
+	fn needs_two_level_changes() {
+		let mut tracker = Tracker::default();
+		assert_eq!(tracker.update(0, Discharging(80)), None);
+		assert_eq!(tracker.update(100, Discharging(80)), None);
+		assert_eq!(tracker.update(200, Discharging(75)), None);
+		// 5% in 600 seconds, the first 200 seconds are not counted as 80% may have been reached
+		// long before
+		assert_eq!(
+			tracker.update(800, Discharging(70)),
+			Some(Estimate::TimeToEmpty(70 * 120))
+		);
+		assert_eq!(
+			tracker.update(900, Discharging(70)),
+			Some(Estimate::TimeToEmpty(70 * 120))
+		);
+	}
+
+	#[test]
+	fn charging() {
+		let mut tracker = Tracker::default();
+		assert_eq!(tracker.update(0, Discharging(50)), None);
+		assert_eq!(tracker.update(10, Charging(50)), None);
+		assert_eq!(tracker.update(100, Charging(55)), None);
+		assert_eq!(
+			tracker.update(400, Charging(60)),
+			Some(Estimate::TimeToFull(40 * 60))
+		);
+		assert_eq!(
+			tracker.update(500, Charging(100)),
+			Some(Estimate::TimeToFull(0))
+		);
+	}
+
+	#[test]
+	fn resets() {
+		let mut tracker = Tracker::default();
+		tracker.update(0, Discharging(80));
+		tracker.update(100, Discharging(75));
+		assert!(tracker.update(200, Discharging(70)).is_some());
+
+		// a bump up starts over
+		assert_eq!(tracker.update(300, Discharging(75)), None);
+		assert_eq!(tracker.update(400, Discharging(70)), None);
+		assert!(tracker.update(500, Discharging(65)).is_some());
+
+		assert_eq!(tracker.update(600, Disconnected), None);
+		assert_eq!(tracker.update(700, Discharging(60)), None);
+	}
+
+	#[test]
+	fn old_changes_are_dropped() {
+		let mut tracker = Tracker::default();
+		tracker.update(0, Discharging(90));
+		tracker.update(100, Discharging(85));
+		tracker.update(200, Discharging(80));
+		// drained much slower since
+		tracker.update(20000, Discharging(75));
+		assert_eq!(
+			tracker.update(30000, Discharging(70)),
+			Some(Estimate::TimeToEmpty(70 * 2000))
+		);
+	}
+}
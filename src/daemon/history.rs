@@ -0,0 +1,111 @@
+use std::{
+	fs::{self, OpenOptions},
+	io::{BufRead, BufReader, ErrorKind, Write},
+	path::PathBuf,
+	sync::Arc,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use futures::future::select_all;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{Address, DaemonState, PodsBattery, config::state_dir, supervisor::ConfigError};
+
+/// A battery change, stored as one JSON line per record.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Record {
+	/// Seconds since the unix epoch.
+	pub time: u64,
+	pub battery: PodsBattery,
+}
+
+pub fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |x| x.as_secs())
+}
+
+/// `$XDG_STATE_HOME/airpodsd/history/<mac_address>.jsonl`, with `:` replaced by `_`.
+pub fn path(addr: Address) -> Option<PathBuf> {
+	state_dir().map(|x| {
+		x.join("history")
+			.join(format!("{}.jsonl", addr.to_string().replace(":", "_")))
+	})
+}
+
+fn append(path: &PathBuf, record: &Record) -> Result<()> {
+	if let Some(dir) = path.parent() {
+		fs::create_dir_all(dir)
+			.with_context(|| format!("failed to create history directory {}", dir.display()))?;
+	}
+
+	let mut line = serde_json::to_vec(record).context("failed to serialize history record")?;
+	line.push(b'\n');
+	OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.and_then(|mut x| x.write_all(&line))
+		.with_context(|| format!("failed to append to history file {}", path.display()))
+}
+
+/// Reads the records of `addr` that are not older than `since`.
+pub fn read(addr: Address, since: u64) -> Result<Vec<Record>> {
+	let path = path(addr).context("neither XDG_STATE_HOME nor HOME is set")?;
+	let file = match fs::File::open(&path) {
+		Ok(file) => file,
+		Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => {
+			return Err(err)
+				.with_context(|| format!("failed to open history file {}", path.display()));
+		}
+	};
+
+	let mut ret = Vec::new();
+	for (i, line) in BufReader::new(file).lines().enumerate() {
+		let line = line.with_context(|| format!("failed to read {}", path.display()))?;
+		match serde_json::from_str::<Record>(&line) {
+			Ok(record) if record.time >= since => ret.push(record),
+			Ok(_) => {}
+			// a line cut short by a crash shouldn't hide the rest of the history
+			Err(err) => warn!("skipping line {} of {}: {}", i + 1, path.display(), err),
+		}
+	}
+	Ok(ret)
+}
+
+/// Appends every battery change of every device to its history file.
+pub async fn history_main(state: Arc<DaemonState>) -> Result<()> {
+	let mut paths = Vec::with_capacity(state.devices.len());
+	for handle in &state.devices {
+		paths.push(
+			path(handle.addr).context(ConfigError("neither XDG_STATE_HOME nor HOME is set"))?,
+		);
+	}
+
+	let mut last_battery = vec![None; state.devices.len()];
+	loop {
+		for ((handle, path), last) in state.devices.iter().zip(&paths).zip(&mut last_battery) {
+			let Some(battery) = handle.status.lock().await.battery else {
+				continue;
+			};
+			if *last == Some(battery) {
+				continue;
+			}
+			*last = Some(battery);
+
+			let record = Record {
+				time: now(),
+				battery,
+			};
+			let path = path.clone();
+			tokio::task::spawn_blocking(move || append(&path, &record))
+				.await
+				.context("failed to join history writer")??;
+		}
+
+		select_all(state.devices.iter().map(|x| x.notify.listen())).await;
+	}
+}
@@ -0,0 +1,289 @@
+use std::{collections::BTreeMap, path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use futures::future::select_all;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command, sync::Semaphore, time::timeout};
+
+use super::{Address, DaemonState, PodsStatus, battery::BatteryPolicy, packet::EarDetectionStatus};
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+	Connected,
+	Disconnected,
+	/// A bud was put into an ear.
+	EarIn,
+	/// A bud was taken out of an ear.
+	EarOut,
+	NoiseModeChanged,
+	/// The lowest bud battery level dropped below the threshold.
+	BatteryLow,
+	/// The case or a bud started charging.
+	ChargingStarted,
+}
+
+impl HookEvent {
+	fn name(&self) -> &'static str {
+		match self {
+			Self::Connected => "connected",
+			Self::Disconnected => "disconnected",
+			Self::EarIn => "ear-in",
+			Self::EarOut => "ear-out",
+			Self::NoiseModeChanged => "noise-mode-changed",
+			Self::BatteryLow => "battery-low",
+			Self::ChargingStarted => "charging-started",
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HooksConfig {
+	/// Seconds after which a hook is killed.
+	pub timeout: u64,
+	/// Number of hooks that may run at once, others wait for their turn.
+	pub max_concurrent: usize,
+	/// Battery percentage below which `battery-low` fires.
+	pub battery_low: u8,
+	/// Executables to run for each event.
+	pub on: BTreeMap<HookEvent, Vec<PathBuf>>,
+}
+
+impl Default for HooksConfig {
+	fn default() -> Self {
+		Self {
+			timeout: 10,
+			max_concurrent: 4,
+			battery_low: 20,
+			on: BTreeMap::new(),
+		}
+	}
+}
+
+/// What hooks receive on stdin.
+#[derive(Serialize)]
+struct HookInput<'a> {
+	event: HookEvent,
+	address: Address,
+	old: &'a PodsStatus,
+	new: &'a PodsStatus,
+}
+
+fn events(old: &PodsStatus, new: &PodsStatus, battery_low: u8) -> Vec<HookEvent> {
+	let mut ret = Vec::new();
+
+	match (old.connected, new.connected) {
+		(false, true) => ret.push(HookEvent::Connected),
+		(true, false) => ret.push(HookEvent::Disconnected),
+		_ => {}
+	}
+
+	if let (Some(old), Some(new)) = (old.ear, new.ear) {
+		let in_ear = |x: EarDetectionStatus| x == EarDetectionStatus::InEar;
+		let pairs = [(old.primary, new.primary), (old.secondary, new.secondary)];
+		if pairs.iter().any(|(old, new)| !in_ear(*old) && in_ear(*new)) {
+			ret.push(HookEvent::EarIn);
+		}
+		if pairs.iter().any(|(old, new)| in_ear(*old) && !in_ear(*new)) {
+			ret.push(HookEvent::EarOut);
+		}
+	}
+
+	if let (Some(old), Some(new)) = (old.noise, new.noise)
+		&& old != new
+	{
+		ret.push(HookEvent::NoiseModeChanged);
+	}
+
+	if let (Some(old), Some(new)) = (old.battery, new.battery) {
+		let (old_level, new_level) = (
+			BatteryPolicy::Min.aggregate(&old, None),
+			BatteryPolicy::Min.aggregate(&new, None),
+		);
+		if let (Some(old_level), Some(new_level)) = (old_level, new_level)
+			&& old_level >= battery_low
+			&& new_level < battery_low
+		{
+			ret.push(HookEvent::BatteryLow);
+		}
+
+		let pairs = [
+			(old.case, new.case),
+			(old.left, new.left),
+			(old.right, new.right),
+		];
+		if pairs
+			.iter()
+			.any(|(old, new)| !old.is_charging() && new.is_charging())
+		{
+			ret.push(HookEvent::ChargingStarted);
+		}
+	}
+
+	ret
+}
+
+async fn run_hook(
+	path: PathBuf,
+	input: Vec<u8>,
+	env: Vec<(&'static str, String)>,
+	limit: Duration,
+) {
+	let mut child = match Command::new(&path)
+		.envs(env)
+		.stdin(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn()
+	{
+		Ok(child) => child,
+		Err(err) => {
+			warn!("failed to run hook {}: {:?}", path.display(), err);
+			return;
+		}
+	};
+
+	let run = async {
+		if let Some(mut stdin) = child.stdin.take() {
+			// the hook may not read its input at all
+			let _ = stdin.write_all(&input).await;
+		}
+		child.wait().await
+	};
+
+	match timeout(limit, run).await {
+		Ok(Ok(status)) if status.success() => {}
+		Ok(Ok(status)) => warn!("hook {} exited with {}", path.display(), status),
+		Ok(Err(err)) => warn!("failed to wait for hook {}: {:?}", path.display(), err),
+		Err(_) => warn!(
+			"hook {} timed out after {:?}, killing it",
+			path.display(),
+			limit
+		),
+	}
+}
+
+/// Runs the configured hooks when the status of a device changes.
+pub async fn hooks_main(state: Arc<DaemonState>, config: HooksConfig) -> Result<()> {
+	let permits = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+	let limit = Duration::from_secs(config.timeout);
+
+	let mut last_status = Vec::with_capacity(state.devices.len());
+	for handle in &state.devices {
+		last_status.push(*handle.status.lock().await);
+	}
+
+	loop {
+		select_all(state.devices.iter().map(|x| x.notify.listen())).await;
+
+		for (handle, last) in state.devices.iter().zip(&mut last_status) {
+			let status = *handle.status.lock().await;
+			let old = std::mem::replace(last, status);
+
+			for event in events(&old, &status, config.battery_low) {
+				let Some(paths) = config.on.get(&event) else {
+					continue;
+				};
+
+				let input = HookInput {
+					event,
+					address: handle.addr,
+					old: &old,
+					new: &status,
+				};
+				let mut stdin =
+					serde_json::to_vec(&input).context("failed to serialize hook input")?;
+				stdin.push(b'\n');
+				let env = vec![
+					("AIRPODSD_EVENT", event.name().to_string()),
+					("AIRPODSD_ADDRESS", handle.addr.to_string()),
+					(
+						"AIRPODSD_OLD_STATUS",
+						serde_json::to_string(&old).context("failed to serialize old status")?,
+					),
+					(
+						"AIRPODSD_NEW_STATUS",
+						serde_json::to_string(&status).context("failed to serialize new status")?,
+					),
+				];
+
+				for path in paths {
+					info!("running {} hook {}", event.name(), path.display());
+					let (path, stdin, env) = (path.clone(), stdin.clone(), env.clone());
+					let permits = permits.clone();
+					tokio::spawn(async move {
+						let Ok(_permit) = permits.acquire_owned().await else {
+							return;
+						};
+						run_hook(path, stdin, env, limit).await;
+					});
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{HookEvent, events};
+	use crate::daemon::{
+		PodsBattery, PodsInEar, PodsStatus,
+		estimate::PodsEstimate,
+		packet::{BatteryStatus, EarDetectionStatus, NoiseControlStatus},
+	};
+
+	fn connected() -> PodsStatus {
+		PodsStatus {
+			connected: true,
+			battery: Some(PodsBattery {
+				case: BatteryStatus::Discharging(50),
+				left: BatteryStatus::Discharging(40),
+				right: BatteryStatus::Discharging(30),
+			}),
+			noise: Some(NoiseControlStatus::Off),
+			ear: Some(PodsInEar {
+				primary: EarDetectionStatus::InEar,
+				secondary: EarDetectionStatus::InEar,
+			}),
+			estimate: PodsEstimate::default(),
+		}
+	}
+
+	#[test]
+	fn connection_events() {
+		assert_eq!(
+			events(&PodsStatus::unknown(), &connected(), 20),
+			[HookEvent::Connected]
+		);
+		assert_eq!(
+			events(&connected(), &PodsStatus::unknown(), 20),
+			[HookEvent::Disconnected]
+		);
+		assert_eq!(events(&connected(), &connected(), 20), []);
+	}
+
+	#[test]
+	fn status_events() {
+		let old = connected();
+
+		let mut new = old;
+		new.ear.as_mut().unwrap().secondary = EarDetectionStatus::InCase;
+		assert_eq!(events(&old, &new, 20), [HookEvent::EarOut]);
+		assert_eq!(events(&new, &old, 20), [HookEvent::EarIn]);
+
+		let mut new = old;
+		new.noise = Some(NoiseControlStatus::NoiseCancellation);
+		assert_eq!(events(&old, &new, 20), [HookEvent::NoiseModeChanged]);
+
+		let mut new = old;
+		new.battery.as_mut().unwrap().right = BatteryStatus::Discharging(19);
+		assert_eq!(events(&old, &new, 20), [HookEvent::BatteryLow]);
+		assert_eq!(events(&new, &old, 20), []);
+
+		let mut new = old;
+		new.battery.as_mut().unwrap().case = BatteryStatus::Charging(50);
+		assert_eq!(events(&old, &new, 20), [HookEvent::ChargingStarted]);
+		assert_eq!(events(&new, &old, 20), []);
+	}
+}
@@ -1,78 +1,375 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use bluez::bluez_main;
+use clap::ValueEnum;
 use event_listener::Event;
-use log::{LevelFilter, info};
+use log::{LevelFilter, info, warn};
 use serde::{Deserialize, Serialize};
-use tokio::{sync::Mutex, task::JoinSet};
+use tokio::sync::{Mutex, mpsc, oneshot};
 
+pub mod battery;
 mod blconn;
 mod bluetooth;
 mod bluez;
+pub mod capture;
+pub mod codec;
+pub mod config;
+pub mod dbus;
+pub mod discovery;
+pub mod estimate;
+pub mod history;
+pub mod hooks;
+pub mod mpris;
+pub mod notifications;
 pub mod packet;
+pub mod protocol;
+pub mod simulator;
+mod supervisor;
+pub mod transport;
 mod unix;
 
+use battery::BatteryPolicy;
 pub use blconn::Address;
 use bluetooth::{bluetooth_main, bluetooth_setup};
-use packet::{BatteryStatus, EarDetectionStatus, NoiseControlStatus};
+use capture::{Capture, replay_main};
+use config::Config;
+use dbus::{DbusBus, dbus_main};
+use discovery::{discover, discover_one};
+use estimate::PodsEstimate;
+use history::history_main;
+use hooks::hooks_main;
+use mpris::{PauseOn, mpris_main};
+use notifications::notifications_main;
+use packet::{BatteryStatus, EarDetectionStatus, NoiseControlStatus, ParsedPacket};
+use protocol::ProtocolError;
+use simulator::simulator_main;
+use supervisor::{HealthState, Supervisor};
 use unix::unix_listener_main;
 
-#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PodsBattery {
 	pub case: BatteryStatus,
 	pub left: BatteryStatus,
 	pub right: BatteryStatus,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PodsInEar {
 	pub primary: EarDetectionStatus,
 	pub secondary: EarDetectionStatus,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PodsStatus {
+	/// Whether the AAP connection to the device is up.
+	pub connected: bool,
 	pub battery: Option<PodsBattery>,
 	pub noise: Option<NoiseControlStatus>,
 	pub ear: Option<PodsInEar>,
+	pub estimate: PodsEstimate,
 }
 
 impl PodsStatus {
 	pub fn unknown() -> Self {
 		Self {
+			connected: false,
 			battery: None,
 			noise: None,
 			ear: None,
+			estimate: PodsEstimate::default(),
 		}
 	}
 }
 
 pub type PodsState = Arc<Mutex<PodsStatus>>;
 
-pub async fn daemon_main(addr: Address) -> Result<()> {
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PacketStats {
+	pub battery: u64,
+	pub noise_control: u64,
+	pub ear_detection: u64,
+	pub unknown: u64,
+	pub malformed: u64,
+}
+
+impl PacketStats {
+	pub fn record(&mut self, packet: &ParsedPacket) {
+		match packet {
+			ParsedPacket::Battery(_) => self.battery += 1,
+			ParsedPacket::NoiseControl(_) => self.noise_control += 1,
+			ParsedPacket::EarDetection { .. } => self.ear_detection += 1,
+			ParsedPacket::Unknown(_) => self.unknown += 1,
+		}
+	}
+}
+
+pub type PacketStatsState = Arc<Mutex<PacketStats>>;
+
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodePolicy {
+	/// Drop the connection to the device when a packet fails to decode.
+	Strict,
+	/// Log packets that fail to decode and treat them as unknown packets.
+	#[default]
+	Lenient,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeviceCommand {
+	SetNoiseControl(NoiseControlStatus),
+}
+
+pub type CommandResult = std::result::Result<(), ProtocolError>;
+pub type CommandRequest = (DeviceCommand, oneshot::Sender<CommandResult>);
+pub type CommandSender = mpsc::Sender<CommandRequest>;
+pub type CommandReceiver = Arc<Mutex<mpsc::Receiver<CommandRequest>>>;
+
+/// State that the subsystems share about a single device.
+#[derive(Clone)]
+pub struct DeviceHandle {
+	pub addr: Address,
+	pub status: PodsState,
+	pub notify: Arc<Event>,
+	pub stats: PacketStatsState,
+	pub commands: CommandSender,
+}
+
+impl DeviceHandle {
+	/// Runs `command` on the device through its bluetooth task.
+	pub async fn run(&self, command: DeviceCommand) -> CommandResult {
+		let (tx, rx) = oneshot::channel();
+		let dropped = || ProtocolError::DeviceError {
+			message: "bluetooth task is not running".to_string(),
+		};
+
+		self.commands
+			.send((command, tx))
+			.await
+			.map_err(|_| dropped())?;
+		rx.await.map_err(|_| dropped())?
+	}
+}
+
+/// State shared by the whole daemon.
+pub struct DaemonState {
+	pub devices: Vec<DeviceHandle>,
+	pub health: HealthState,
+	pub battery_policy: Mutex<BatteryPolicy>,
+	/// Notified when a setting that is changeable at runtime changes.
+	pub settings_changed: Event,
+}
+
+/// Creates the state of every device in `addrs`, skipping duplicates.
+fn device_handles(addrs: Vec<Address>) -> Result<(Vec<DeviceHandle>, Vec<CommandReceiver>)> {
+	let mut devices = Vec::with_capacity(addrs.len());
+	let mut receivers = Vec::with_capacity(addrs.len());
+	for addr in addrs {
+		if devices.iter().any(|x: &DeviceHandle| x.addr == addr) {
+			continue;
+		}
+
+		let (commands_tx, commands_rx) = mpsc::channel(16);
+		devices.push(DeviceHandle {
+			addr,
+			status: Arc::new(Mutex::new(PodsStatus::unknown())),
+			notify: Arc::new(Event::new()),
+			stats: Arc::new(Mutex::new(PacketStats::default())),
+			commands: commands_tx,
+		});
+		receivers.push(Arc::new(Mutex::new(commands_rx)));
+	}
+
+	// every per-device task waits on all of the devices, which never finishes with none
+	if devices.is_empty() {
+		bail!(
+			"no devices to manage, pass a MAC address, add devices to the config file or pair AirPods for --all-paired"
+		);
+	}
+	Ok((devices, receivers))
+}
+
+pub async fn daemon_main(config: Config) -> Result<()> {
 	env_logger::builder()
 		.filter_level(LevelFilter::Debug)
 		.parse_default_env()
 		.init();
 
-	let status = Arc::new(Mutex::new(PodsStatus::unknown()));
-	let notify = Arc::new(Event::new());
-	let mut set = JoinSet::new();
+	let health = Arc::new(Mutex::new(BTreeMap::new()));
+	let mut supervisor = Supervisor::new(health.clone());
+
+	let capture = match &config.record {
+		Some(path) => Some(Arc::new(Capture::create(path).await?)),
+		None => None,
+	};
+	let replay = match &config.replay {
+		Some(path) => Some(Arc::new(capture::read(path)?)),
+		None => None,
+	};
+
+	// simulated and replayed devices don't need bluetooth at all
+	let adapter = if config.simulate || replay.is_some() {
+		None
+	} else {
+		Some(
+			bluetooth_setup(config.adapter.as_deref())
+				.await
+				.context("failed to set up bluetooth")?,
+		)
+	};
+	if adapter.is_none() && config.all_paired {
+		// only reachable through the config file, the command line rejects the combination
+		warn!("ignoring all-paired for simulated or replayed devices");
+	}
+
+	let mut addrs = config.devices.clone();
+	match &adapter {
+		Some(adapter) if config.all_paired => addrs.extend(
+			discover(adapter)
+				.await
+				.context("failed to discover paired devices")?,
+		),
+		Some(adapter) if addrs.is_empty() => addrs.push(
+			discover_one(adapter)
+				.await
+				.context("failed to discover device")?,
+		),
+		None if addrs.is_empty() => match &replay {
+			Some(records) => addrs.extend(records.iter().map(|x| x.address)),
+			None => addrs.push(simulator::DEFAULT_ADDRESS.parse()?),
+		},
+		_ => {}
+	}
+
+	let (devices, receivers) = device_handles(addrs)?;
+	let state = Arc::new(DaemonState {
+		devices,
+		health,
+		battery_policy: Mutex::new(config.battery_policy),
+		settings_changed: Event::new(),
+	});
+
+	for (handle, commands) in state.devices.iter().zip(receivers) {
+		let addr = handle.addr;
+		let policy = config.decode_policy;
+		if let Some(adapter) = &adapter {
+			let device = adapter
+				.device(bluer::Address::new(addr.into_inner()))
+				.with_context(|| format!("failed to get device {addr}"))?;
+
+			supervisor.spawn(format!("bluetooth {addr}"), {
+				let (handle, adapter, capture) = (handle.clone(), adapter.clone(), capture.clone());
+				move || {
+					bluetooth_main(
+						handle.clone(),
+						adapter.clone(),
+						device.clone(),
+						commands.clone(),
+						policy,
+						capture.clone(),
+					)
+				}
+			});
+		} else if let Some(records) = &replay {
+			supervisor.spawn(format!("replay {addr}"), {
+				let (handle, records) = (handle.clone(), records.clone());
+				move || replay_main(handle.clone(), commands.clone(), policy, records.clone())
+			});
+		} else {
+			supervisor.spawn(format!("simulator {addr}"), {
+				let (handle, capture) = (handle.clone(), capture.clone());
+				move || simulator_main(handle.clone(), commands.clone(), policy, capture.clone())
+			});
+		}
+		supervisor.spawn(format!("unix {addr}"), {
+			let (handle, state) = (handle.clone(), state.clone());
+			move || unix_listener_main(handle.clone(), state.clone())
+		});
+	}
+	// there is no bluetoothd to hand the batteries of simulated and replayed devices to
+	if let Some(adapter) = &adapter {
+		supervisor.spawn("bluez", {
+			let (state, name) = (state.clone(), adapter.name().to_string());
+			move || bluez_main(state.clone(), name.clone())
+		});
+	}
+	supervisor.spawn("history", {
+		let state = state.clone();
+		move || history_main(state.clone())
+	});
+	if config.dbus_bus != DbusBus::None {
+		supervisor.spawn("dbus", {
+			let state = state.clone();
+			let bus = config.dbus_bus;
+			move || dbus_main(state.clone(), bus)
+		});
+	}
+	if config.ear_detection.pause_on != PauseOn::Never {
+		supervisor.spawn("mpris", {
+			let state = state.clone();
+			let config = config.ear_detection.clone();
+			move || mpris_main(state.clone(), config.clone())
+		});
+	}
+	if !config.hooks.on.is_empty() {
+		supervisor.spawn("hooks", {
+			let state = state.clone();
+			let config = config.hooks.clone();
+			move || hooks_main(state.clone(), config.clone())
+		});
+	}
+	if config.notifications.enabled {
+		supervisor.spawn("notifications", {
+			let state = state.clone();
+			let config = config.notifications.clone();
+			move || notifications_main(state.clone(), config.clone())
+		});
+	}
+
+	info!("daemon started for {} device(s)", state.devices.len());
+
+	supervisor.wait().await
+}
+
+#[cfg(test)]
+mod tests {
+	use std::process::Stdio;
 
-	let (device, name) = bluetooth_setup(addr)
-		.await
-		.context("failed to set up bluetooth")?;
+	use tokio::{
+		io::{AsyncBufReadExt, BufReader},
+		process::{Child, Command},
+	};
 
-	set.spawn(bluetooth_main(addr, status.clone(), notify.clone(), device));
-	set.spawn(bluez_main(addr, status.clone(), notify.clone(), name));
-	set.spawn(unix_listener_main(addr, status, notify));
+	use super::{Address, device_handles};
 
-	info!("daemon started");
+	/// Starts a bus of its own for a test, which stops when the returned child is dropped.
+	pub(super) async fn private_bus() -> (Child, String) {
+		let mut daemon = Command::new("dbus-daemon")
+			.args(["--session", "--nofork", "--print-address"])
+			.stdout(Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()
+			.expect("dbus-daemon is needed to run this test");
+		let mut address = String::new();
+		BufReader::new(daemon.stdout.take().unwrap())
+			.read_line(&mut address)
+			.await
+			.unwrap();
+		(daemon, address.trim().to_string())
+	}
 
-	while let Some(ret) = set.join_next().await {
-		ret.context("failed to wait for task")??;
+	#[test]
+	fn device_handles_skip_duplicates() {
+		let a: Address = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+		let b: Address = "11:22:33:44:55:66".parse().unwrap();
+		let (devices, receivers) = device_handles(vec![a, b, a]).unwrap();
+		assert_eq!(devices.iter().map(|x| x.addr).collect::<Vec<_>>(), [a, b]);
+		assert_eq!(receivers.len(), 2);
 	}
 
-	Ok(())
+	#[test]
+	fn no_devices_is_an_error() {
+		assert!(device_handles(Vec::new()).is_err());
+	}
 }
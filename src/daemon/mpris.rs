@@ -0,0 +1,295 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use futures::future::select_all;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use zbus::{Connection, fdo::DBusProxy, proxy, proxy::CacheProperties};
+
+use super::{DaemonState, PodsInEar, packet::EarDetectionStatus};
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+#[proxy(
+	interface = "org.mpris.MediaPlayer2.Player",
+	default_path = "/org/mpris/MediaPlayer2"
+)]
+trait Player {
+	fn pause(&self) -> zbus::Result<()>;
+	fn play(&self) -> zbus::Result<()>;
+
+	#[zbus(property)]
+	fn playback_status(&self) -> zbus::Result<String>;
+}
+
+/// When media players are paused as buds are taken out of the ears.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PauseOn {
+	/// Never pause.
+	#[default]
+	Never,
+	/// Pause when either bud leaves the ear.
+	OneOut,
+	/// Pause only when both buds have left the ears.
+	BothOut,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum EarAction {
+	Pause,
+	Resume,
+}
+
+impl PauseOn {
+	fn is_out(&self, ear: &PodsInEar) -> bool {
+		let out = |x: EarDetectionStatus| x != EarDetectionStatus::InEar;
+		match self {
+			Self::Never => false,
+			Self::OneOut => out(ear.primary) || out(ear.secondary),
+			Self::BothOut => out(ear.primary) && out(ear.secondary),
+		}
+	}
+
+	/// Decides what to do when the ear state changes from `old` to `new`. Nothing is done when
+	/// either state is unknown, such as right after connecting.
+	fn action(&self, old: Option<PodsInEar>, new: Option<PodsInEar>) -> Option<EarAction> {
+		let (old, new) = (old?, new?);
+		match (self.is_out(&old), self.is_out(&new)) {
+			(false, true) => Some(EarAction::Pause),
+			(true, false) => Some(EarAction::Resume),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EarDetectionConfig {
+	pub pause_on: PauseOn,
+	/// Resume the players that were paused once the buds are back in.
+	pub resume: bool,
+}
+
+impl Default for EarDetectionConfig {
+	fn default() -> Self {
+		Self {
+			pause_on: PauseOn::default(),
+			resume: true,
+		}
+	}
+}
+
+async fn player<'a>(conn: &Connection, name: &'a str) -> Result<PlayerProxy<'a>> {
+	PlayerProxy::builder(conn)
+		.destination(name)
+		.context("failed to set player destination")?
+		.cache_properties(CacheProperties::No)
+		.build()
+		.await
+		.context("failed to create player proxy")
+}
+
+/// Pauses every playing MPRIS player and returns the names of the ones that were paused.
+async fn pause_players(conn: &Connection) -> Result<Vec<String>> {
+	let names = DBusProxy::new(conn)
+		.await
+		.context("failed to create d-bus proxy")?
+		.list_names()
+		.await
+		.context("failed to list bus names")?;
+
+	let mut paused = Vec::new();
+	for name in names.iter().filter(|x| x.starts_with(MPRIS_PREFIX)) {
+		let player = player(conn, name).await?;
+		match player.playback_status().await {
+			Ok(status) if status == "Playing" => {}
+			Ok(_) => continue,
+			Err(err) => {
+				warn!("failed to get playback status of {}: {:?}", name, err);
+				continue;
+			}
+		}
+
+		if let Err(err) = player.pause().await {
+			warn!("failed to pause {}: {:?}", name, err);
+			continue;
+		}
+		info!("paused {}", name);
+		paused.push(name.to_string());
+	}
+
+	Ok(paused)
+}
+
+/// Resumes the players in `names` that are still paused.
+async fn resume_players(conn: &Connection, names: &[String]) -> Result<()> {
+	for name in names {
+		let player = player(conn, name).await?;
+		// the player may have been stopped or closed in the meantime
+		if !player.playback_status().await.is_ok_and(|x| x == "Paused") {
+			continue;
+		}
+
+		if let Err(err) = player.play().await {
+			warn!("failed to resume {}: {:?}", name, err);
+			continue;
+		}
+		info!("resumed {}", name);
+	}
+
+	Ok(())
+}
+
+/// Pauses and resumes MPRIS players on the session bus as buds leave and go back into the ears.
+pub async fn mpris_main(state: Arc<DaemonState>, config: EarDetectionConfig) -> Result<()> {
+	let conn = Connection::session()
+		.await
+		.context("failed to connect to d-bus session bus")?;
+
+	let mut last_ear = Vec::with_capacity(state.devices.len());
+	for handle in &state.devices {
+		last_ear.push(handle.status.lock().await.ear);
+	}
+	let mut paused = vec![Vec::new(); state.devices.len()];
+
+	loop {
+		select_all(state.devices.iter().map(|x| x.notify.listen())).await;
+
+		for ((handle, last), paused) in state.devices.iter().zip(&mut last_ear).zip(&mut paused) {
+			let ear = handle.status.lock().await.ear;
+			let action = config.pause_on.action(*last, ear);
+			*last = ear;
+
+			match action {
+				Some(EarAction::Pause) => {
+					info!("{} left the ear, pausing media players", handle.addr);
+					paused.extend(pause_players(&conn).await?);
+				}
+				Some(EarAction::Resume) => {
+					if config.resume {
+						info!("{} is back in the ear, resuming media players", handle.addr);
+						resume_players(&conn, paused).await?;
+					}
+					paused.clear();
+				}
+				None => {}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use zbus::{Connection, conn::Builder as ConnBuilder, interface};
+
+	use super::{EarAction, PauseOn, pause_players, resume_players};
+	use crate::daemon::{PodsInEar, packet::EarDetectionStatus, tests::private_bus};
+
+	fn ear(primary: EarDetectionStatus, secondary: EarDetectionStatus) -> Option<PodsInEar> {
+		Some(PodsInEar { primary, secondary })
+	}
+
+	#[test]
+	fn actions() {
+		use EarDetectionStatus::*;
+
+		let both_in = ear(InEar, InEar);
+		let one_out = ear(InEar, OutOfEar);
+		let both_out = ear(OutOfEar, InCase);
+
+		assert_eq!(
+			PauseOn::OneOut.action(both_in, one_out),
+			Some(EarAction::Pause)
+		);
+		assert_eq!(PauseOn::OneOut.action(one_out, both_out), None);
+		assert_eq!(
+			PauseOn::OneOut.action(one_out, both_in),
+			Some(EarAction::Resume)
+		);
+
+		assert_eq!(PauseOn::BothOut.action(both_in, one_out), None);
+		assert_eq!(
+			PauseOn::BothOut.action(one_out, both_out),
+			Some(EarAction::Pause)
+		);
+		assert_eq!(
+			PauseOn::BothOut.action(both_out, one_out),
+			Some(EarAction::Resume)
+		);
+
+		assert_eq!(PauseOn::Never.action(both_in, both_out), None);
+		assert_eq!(PauseOn::OneOut.action(None, both_out), None);
+		assert_eq!(PauseOn::OneOut.action(both_in, None), None);
+	}
+
+	struct FakePlayer {
+		status: String,
+	}
+
+	#[interface(name = "org.mpris.MediaPlayer2.Player")]
+	impl FakePlayer {
+		fn pause(&mut self) {
+			self.status = "Paused".to_string();
+		}
+
+		fn play(&mut self) {
+			self.status = "Playing".to_string();
+		}
+
+		#[zbus(property)]
+		fn playback_status(&self) -> &str {
+			&self.status
+		}
+	}
+
+	async fn fake_player(address: &str, name: &str, status: &str) -> Connection {
+		ConnBuilder::address(address)
+			.unwrap()
+			.name(format!("org.mpris.MediaPlayer2.{name}"))
+			.unwrap()
+			.serve_at(
+				"/org/mpris/MediaPlayer2",
+				FakePlayer {
+					status: status.to_string(),
+				},
+			)
+			.unwrap()
+			.build()
+			.await
+			.unwrap()
+	}
+
+	async fn status(conn: &Connection) -> String {
+		conn.object_server()
+			.interface::<_, FakePlayer>("/org/mpris/MediaPlayer2")
+			.await
+			.unwrap()
+			.get()
+			.await
+			.status
+			.clone()
+	}
+
+	#[tokio::test]
+	async fn pauses_and_resumes_players() {
+		let (_daemon, address) = private_bus().await;
+		let playing = fake_player(&address, "playing", "Playing").await;
+		let paused = fake_player(&address, "paused", "Paused").await;
+		let conn = ConnBuilder::address(address.as_str())
+			.unwrap()
+			.build()
+			.await
+			.unwrap();
+
+		let names = pause_players(&conn).await.unwrap();
+		assert_eq!(names, ["org.mpris.MediaPlayer2.playing"]);
+		assert_eq!(status(&playing).await, "Paused");
+		assert_eq!(status(&paused).await, "Paused");
+
+		resume_players(&conn, &names).await.unwrap();
+		assert_eq!(status(&playing).await, "Playing");
+		assert_eq!(status(&paused).await, "Paused");
+	}
+}
@@ -0,0 +1,323 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use futures::future::select_all;
+use log::{info, warn};
+use serde::Deserialize;
+use zbus::{Connection, proxy, zvariant::Value};
+
+use super::{DaemonState, PodsBattery, PodsStatus, packet::BatteryStatus};
+
+/// How far a level has to rise above the last threshold that was notified before that threshold
+/// can be notified again, so that a level going back and forth doesn't spam notifications.
+const HYSTERESIS: u8 = 5;
+
+#[proxy(
+	interface = "org.freedesktop.Notifications",
+	default_service = "org.freedesktop.Notifications",
+	default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+	#[allow(clippy::too_many_arguments)]
+	fn notify(
+		&self,
+		app_name: &str,
+		replaces_id: u32,
+		app_icon: &str,
+		summary: &str,
+		body: &str,
+		actions: &[&str],
+		hints: HashMap<&str, Value<'_>>,
+		expire_timeout: i32,
+	) -> zbus::Result<u32>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NotificationsConfig {
+	pub enabled: bool,
+	/// Battery percentages at which a bud or the case is reported as low.
+	pub thresholds: Vec<u8>,
+	/// Notify when the buds connect.
+	pub connected: bool,
+	/// Notify when a bud or the case finishes charging.
+	pub charged: bool,
+}
+
+impl Default for NotificationsConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			thresholds: vec![20, 10, 5],
+			connected: true,
+			charged: true,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Notification {
+	summary: String,
+	body: String,
+	/// Replaces the previous low battery notification instead of stacking up.
+	low_battery: bool,
+	critical: bool,
+}
+
+/// Notification state of the case or a bud.
+#[derive(Debug, Default, Clone, Copy)]
+struct ComponentState {
+	/// Lowest threshold that was notified.
+	low: Option<u8>,
+	/// Whether the component was seen charging below 100%.
+	charging: bool,
+}
+
+/// Decides which notifications to send for a single device.
+#[derive(Debug, Default)]
+struct Notifier {
+	connected: bool,
+	/// Waiting for the battery levels to show them in the connected notification.
+	announce: bool,
+	components: [ComponentState; 3],
+}
+
+fn level(status: BatteryStatus) -> String {
+	status
+		.as_percent()
+		.map_or_else(|| "unknown".to_string(), |x| format!("{x}%"))
+}
+
+impl Notifier {
+	fn update(&mut self, config: &NotificationsConfig, status: &PodsStatus) -> Vec<Notification> {
+		let mut ret = Vec::new();
+
+		if status.connected != self.connected {
+			self.connected = status.connected;
+			self.announce = status.connected && config.connected;
+		}
+
+		let Some(battery) = status.battery else {
+			return ret;
+		};
+
+		if self.announce {
+			self.announce = false;
+			ret.push(Notification {
+				summary: "AirPods connected".to_string(),
+				body: format!(
+					"Left {}, right {}, case {}",
+					level(battery.left),
+					level(battery.right),
+					level(battery.case)
+				),
+				low_battery: false,
+				critical: false,
+			});
+		}
+
+		let PodsBattery { case, left, right } = battery;
+		let components = [("Left bud", left), ("Right bud", right), ("Case", case)];
+		let lowest = config.thresholds.iter().min().copied();
+		for ((name, battery), state) in components.iter().zip(&mut self.components) {
+			let Some(percent) = battery.as_percent() else {
+				continue;
+			};
+
+			if battery.is_charging() {
+				state.low = None;
+				if percent < 100 {
+					state.charging = true;
+				} else if state.charging {
+					state.charging = false;
+					if config.charged {
+						ret.push(Notification {
+							summary: "AirPods charged".to_string(),
+							body: format!("{name} is fully charged"),
+							low_battery: false,
+							critical: false,
+						});
+					}
+				}
+				continue;
+			}
+			state.charging = false;
+
+			let crossed = config
+				.thresholds
+				.iter()
+				.filter(|x| percent <= **x)
+				.min()
+				.copied();
+			match (state.low, crossed) {
+				// rearm silently so that only crossing a threshold again notifies
+				(Some(low), _) if percent > low.saturating_add(HYSTERESIS) => state.low = crossed,
+				(low, Some(crossed)) if low.is_none_or(|x| crossed < x) => {
+					state.low = Some(crossed);
+					ret.push(Notification {
+						summary: "AirPods battery low".to_string(),
+						body: format!("{name} is at {percent}%"),
+						low_battery: true,
+						critical: Some(crossed) == lowest,
+					});
+				}
+				_ => {}
+			}
+		}
+
+		ret
+	}
+}
+
+async fn send(
+	proxy: &NotificationsProxy<'_>,
+	notification: &Notification,
+	replaces_id: u32,
+) -> zbus::Result<u32> {
+	let mut hints = HashMap::new();
+	hints.insert(
+		"urgency",
+		Value::U8(if notification.critical { 2 } else { 1 }),
+	);
+	proxy
+		.notify(
+			"airpodsd",
+			replaces_id,
+			"audio-headphones",
+			&notification.summary,
+			&notification.body,
+			&[],
+			hints,
+			-1,
+		)
+		.await
+}
+
+/// Sends desktop notifications about the battery levels and connection of every device.
+pub async fn notifications_main(
+	state: Arc<DaemonState>,
+	config: NotificationsConfig,
+) -> Result<()> {
+	let conn = Connection::session()
+		.await
+		.context("failed to connect to d-bus session bus")?;
+	let proxy = NotificationsProxy::new(&conn)
+		.await
+		.context("failed to create notifications proxy")?;
+
+	let mut notifiers: Vec<Notifier> = state.devices.iter().map(|_| Notifier::default()).collect();
+	let mut low_battery_ids = vec![0; state.devices.len()];
+	loop {
+		for ((handle, notifier), low_battery_id) in state
+			.devices
+			.iter()
+			.zip(&mut notifiers)
+			.zip(&mut low_battery_ids)
+		{
+			let status = *handle.status.lock().await;
+			for notification in notifier.update(&config, &status) {
+				info!("notifying about {}: {}", handle.addr, notification.body);
+				let replaces_id = if notification.low_battery {
+					*low_battery_id
+				} else {
+					0
+				};
+				// a missing notification server shouldn't take the daemon down
+				match send(&proxy, &notification, replaces_id).await {
+					Ok(id) if notification.low_battery => *low_battery_id = id,
+					Ok(_) => {}
+					Err(err) => warn!("failed to send notification: {:?}", err),
+				}
+			}
+		}
+
+		select_all(state.devices.iter().map(|x| x.notify.listen())).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{NotificationsConfig, Notifier};
+	use crate::daemon::{PodsBattery, PodsStatus, estimate::PodsEstimate, packet::BatteryStatus};
+
+	fn status(left: BatteryStatus, right: BatteryStatus, case: BatteryStatus) -> PodsStatus {
+		PodsStatus {
+			connected: true,
+			battery: Some(PodsBattery { case, left, right }),
+			noise: None,
+			ear: None,
+			estimate: PodsEstimate::default(),
+		}
+	}
+
+	fn bodies(notifier: &mut Notifier, status: PodsStatus) -> Vec<String> {
+		notifier
+			.update(&NotificationsConfig::default(), &status)
+			.into_iter()
+			.map(|x| x.body)
+			.collect()
+	}
+
+	#[test]
+	fn connected() {
+		use BatteryStatus::*;
+
+		let mut notifier = Notifier::default();
+		assert!(bodies(&mut notifier, PodsStatus::unknown()).is_empty());
+
+		let mut connected = PodsStatus::unknown();
+		connected.connected = true;
+		assert!(bodies(&mut notifier, connected).is_empty());
+
+		let full = status(Discharging(80), Discharging(75), Unknown);
+		assert_eq!(
+			bodies(&mut notifier, full),
+			["Left 80%, right 75%, case unknown"]
+		);
+		assert!(bodies(&mut notifier, full).is_empty());
+	}
+
+	#[test]
+	fn thresholds_are_deduplicated() {
+		use BatteryStatus::*;
+
+		let mut notifier = Notifier {
+			connected: true,
+			..Default::default()
+		};
+		let at = |x| status(Discharging(x), Disconnected, Disconnected);
+
+		assert!(bodies(&mut notifier, at(21)).is_empty());
+		assert_eq!(bodies(&mut notifier, at(20)), ["Left bud is at 20%"]);
+		assert!(bodies(&mut notifier, at(21)).is_empty());
+		assert!(bodies(&mut notifier, at(19)).is_empty());
+		assert!(bodies(&mut notifier, at(20)).is_empty());
+		assert_eq!(bodies(&mut notifier, at(10)), ["Left bud is at 10%"]);
+		assert_eq!(bodies(&mut notifier, at(4)), ["Left bud is at 4%"]);
+		assert!(bodies(&mut notifier, at(3)).is_empty());
+
+		// rising well above the threshold rearms it
+		assert!(bodies(&mut notifier, at(30)).is_empty());
+		assert_eq!(bodies(&mut notifier, at(20)), ["Left bud is at 20%"]);
+	}
+
+	#[test]
+	fn charge_complete() {
+		use BatteryStatus::*;
+
+		let mut notifier = Notifier {
+			connected: true,
+			..Default::default()
+		};
+		let case = |x| status(Disconnected, Disconnected, x);
+
+		assert!(bodies(&mut notifier, case(Charging(100))).is_empty());
+		assert!(bodies(&mut notifier, case(Charging(98))).is_empty());
+		assert_eq!(
+			bodies(&mut notifier, case(Charging(100))),
+			["Case is fully charged"]
+		);
+		assert!(bodies(&mut notifier, case(Charging(100))).is_empty());
+		assert!(bodies(&mut notifier, case(Discharging(100))).is_empty());
+	}
+}
@@ -1,14 +1,38 @@
+use std::fmt::Display;
+
 use anyhow::{Context, Result, bail};
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
+/// Error of a packet that failed to decode.
+///
+/// The decoders only consume a field once it decoded, so `offset` is where the field that
+/// failed starts.
+#[derive(Debug)]
+pub struct DecodeError {
+	pub offset: usize,
+	pub error: anyhow::Error,
+}
+
+impl Display for DecodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "at byte {}: {:#}", self.offset, self.error)
+	}
+}
+
+impl std::error::Error for DecodeError {}
+
 trait Decode {
 	fn decode(data: &mut Bytes) -> Result<Self>
 	where
 		Self: Sized;
 }
 
+trait Encode {
+	fn encode(&self, data: &mut BytesMut);
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BatteryComponent {
 	Case,
@@ -16,14 +40,30 @@ pub enum BatteryComponent {
 	Right,
 }
 
+impl Encode for BatteryComponent {
+	fn encode(&self, data: &mut BytesMut) {
+		data.put_u8(match self {
+			Self::Case => 0x08,
+			Self::Left => 0x04,
+			Self::Right => 0x02,
+		});
+	}
+}
+
 impl Decode for BatteryComponent {
 	fn decode(data: &mut Bytes) -> Result<Self> {
-		Ok(match data.get_u8() {
+		if data.remaining() < 1 {
+			bail!("packet too small");
+		}
+
+		let ret = match data[0] {
 			0x08 => Self::Case,
 			0x04 => Self::Left,
 			0x02 => Self::Right,
 			x => bail!("invalid battery component: {:x?}", x),
-		})
+		};
+		data.advance(1);
+		Ok(ret)
 	}
 }
 
@@ -42,17 +82,38 @@ impl BatteryStatus {
 			Self::Unknown | Self::Disconnected => None,
 		}
 	}
+
+	pub fn is_charging(&self) -> bool {
+		matches!(self, Self::Charging(_))
+	}
+}
+
+impl Encode for BatteryStatus {
+	fn encode(&self, data: &mut BytesMut) {
+		data.put_slice(&match self {
+			Self::Unknown => [0x00, 0x00],
+			Self::Charging(x) => [*x, 0x01],
+			Self::Discharging(x) => [*x, 0x02],
+			Self::Disconnected => [0x00, 0x04],
+		});
+	}
 }
 
 impl Decode for BatteryStatus {
 	fn decode(data: &mut Bytes) -> Result<Self> {
-		Ok(match (data.get_u8(), data.get_u8()) {
+		if data.remaining() < 2 {
+			bail!("packet too small");
+		}
+
+		let ret = match (data[0], data[1]) {
 			(_, 0x00) | (_, 0x03) => BatteryStatus::Unknown,
 			(x, 0x01) => BatteryStatus::Charging(x),
 			(x, 0x02) => BatteryStatus::Discharging(x),
 			(_, 0x04) => BatteryStatus::Disconnected,
 			x => bail!("invalid battery status: {:x?}", x),
-		})
+		};
+		data.advance(2);
+		Ok(ret)
 	}
 }
 
@@ -62,6 +123,15 @@ pub struct Battery {
 	pub status: BatteryStatus,
 }
 
+impl Encode for Battery {
+	fn encode(&self, data: &mut BytesMut) {
+		self.component.encode(data);
+		data.put_u8(0x01);
+		self.status.encode(data);
+		data.put_u8(0x01);
+	}
+}
+
 impl Decode for Battery {
 	fn decode(data: &mut Bytes) -> Result<Self> {
 		if data.remaining() < 5 {
@@ -70,15 +140,17 @@ impl Decode for Battery {
 
 		let component = BatteryComponent::decode(data)?;
 
-		if data.get_u8() != 0x01 {
+		if data[0] != 0x01 {
 			bail!("spacer between component and level is not 0x01");
 		}
+		data.advance(1);
 
 		let status = BatteryStatus::decode(data)?;
 
-		if data.get_u8() != 0x01 {
+		if data[0] != 0x01 {
 			bail!("spacer after status is not 0x01");
 		}
+		data.advance(1);
 
 		Ok(Self { component, status })
 	}
@@ -92,19 +164,32 @@ pub enum NoiseControlStatus {
 	AdaptiveTransparency,
 }
 
+impl Encode for NoiseControlStatus {
+	fn encode(&self, data: &mut BytesMut) {
+		data.put_u8(match self {
+			Self::Off => 0x01,
+			Self::NoiseCancellation => 0x02,
+			Self::Transparency => 0x03,
+			Self::AdaptiveTransparency => 0x04,
+		});
+	}
+}
+
 impl Decode for NoiseControlStatus {
 	fn decode(data: &mut Bytes) -> Result<Self> {
 		if data.remaining() < 1 {
 			bail!("packet too small");
 		}
 
-		Ok(match data.get_u8() {
+		let ret = match data[0] {
 			0x01 => Self::Off,
 			0x02 => Self::NoiseCancellation,
 			0x03 => Self::Transparency,
 			0x04 => Self::AdaptiveTransparency,
 			x => bail!("invalid noise control status: {:x?}", x),
-		})
+		};
+		data.advance(1);
+		Ok(ret)
 	}
 }
 
@@ -115,23 +200,35 @@ pub enum EarDetectionStatus {
 	InCase,
 }
 
+impl Encode for EarDetectionStatus {
+	fn encode(&self, data: &mut BytesMut) {
+		data.put_u8(match self {
+			Self::InEar => 0x00,
+			Self::OutOfEar => 0x01,
+			Self::InCase => 0x02,
+		});
+	}
+}
+
 impl Decode for EarDetectionStatus {
 	fn decode(data: &mut Bytes) -> Result<Self> {
 		if data.remaining() < 1 {
 			bail!("packet too small");
 		}
 
-		Ok(match data.get_u8() {
+		let ret = match data[0] {
 			0x00 => Self::InEar,
 			0x01 => Self::OutOfEar,
 			0x02 => Self::InCase,
 			0x03 => Self::OutOfEar, // TODO find the real value for this
 			x => bail!("invalid ear detection status: {:x?}", x),
-		})
+		};
+		data.advance(1);
+		Ok(ret)
 	}
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParsedPacket {
 	Battery(Vec<Battery>),
 	NoiseControl(NoiseControlStatus),
@@ -139,10 +236,33 @@ pub enum ParsedPacket {
 		primary: EarDetectionStatus,
 		secondary: EarDetectionStatus,
 	},
+	/// A packet that was not understood, carrying its raw bytes.
+	Unknown(Bytes),
 }
 
 impl ParsedPacket {
-	pub fn decode(mut data: Bytes) -> Result<Option<Self>> {
+	/// Encodes the packet like the device sends it, which the simulator uses.
+	pub fn to_bytes(&self) -> Bytes {
+		let mut data = BytesMut::with_capacity(32);
+		self.encode(&mut data);
+		data.freeze()
+	}
+
+	/// Decodes a packet from the device, failing with a [`DecodeError`].
+	pub fn decode(mut data: Bytes) -> Result<Self> {
+		let len = data.len();
+		Self::decode_fields(&mut data).map_err(|error| {
+			DecodeError {
+				offset: len - data.remaining(),
+				error,
+			}
+			.into()
+		})
+	}
+
+	fn decode_fields(data: &mut Bytes) -> Result<Self> {
+		let raw = data.clone();
+
 		if data.remaining() < 6 {
 			bail!("packet is too small");
 		}
@@ -151,7 +271,7 @@ impl ParsedPacket {
 			[0x04, 0x00, 0x04, 0x00] => {}
 			x => {
 				warn!("ignoring packet with invalid header {:x?}: {:x?}", x, data);
-				return Ok(None);
+				return Ok(Self::Unknown(raw));
 			}
 		}
 
@@ -165,12 +285,12 @@ impl ParsedPacket {
 				let mut vec = Vec::with_capacity(3);
 
 				for _ in 0..data.get_u8() {
-					vec.push(Battery::decode(&mut data).context("failed to parse battery")?);
+					vec.push(Battery::decode(data).context("failed to parse battery")?);
 				}
 
 				info!("received battery status: {:?}", vec);
 
-				Ok(Some(Self::Battery(vec)))
+				Ok(Self::Battery(vec))
 			}
 			[0x09, 0x00] => {
 				// Noise control
@@ -180,10 +300,10 @@ impl ParsedPacket {
 
 				match data.get_u8() {
 					0x0D => {
-						let decoded = NoiseControlStatus::decode(&mut data)
+						let decoded = NoiseControlStatus::decode(data)
 							.context("failed to parse noise control status")?;
 						info!("received noise control status: {:?}", decoded);
-						Ok(Some(Self::NoiseControl(decoded)))
+						Ok(Self::NoiseControl(decoded))
 					}
 					x => {
 						warn!(
@@ -191,24 +311,325 @@ impl ParsedPacket {
 							x, data
 						);
 						// some other packet also has 0x09 0x00 but not 0x0D so we ignore in this case
-						Ok(None)
+						Ok(Self::Unknown(raw))
 					}
 				}
 			}
 			[0x06, 0x00] => {
 				// Ear detection
-				let primary = EarDetectionStatus::decode(&mut data)
+				let primary = EarDetectionStatus::decode(data)
 					.context("failed to parse primary ear detection status")?;
-				let secondary = EarDetectionStatus::decode(&mut data)
+				let secondary = EarDetectionStatus::decode(data)
 					.context("failed to parse secondary ear detection status")?;
 
-				info!("received ear detection status: primary {:?} secondary {:?}", primary, secondary);
-				Ok(Some(Self::EarDetection { primary, secondary }))
+				info!(
+					"received ear detection status: primary {:?} secondary {:?}",
+					primary, secondary
+				);
+				Ok(Self::EarDetection { primary, secondary })
 			}
 			x => {
 				warn!("ignoring unknown packet of type {:x?}: {:x?}", x, data);
-				Ok(None)
+				Ok(Self::Unknown(raw))
 			}
 		}
 	}
 }
+
+impl Encode for ParsedPacket {
+	fn encode(&self, data: &mut BytesMut) {
+		match self {
+			Self::Battery(batteries) => {
+				data.put_slice(&[0x04, 0x00, 0x04, 0x00, 0x04, 0x00]);
+				data.put_u8(batteries.len() as u8);
+				for battery in batteries {
+					battery.encode(data);
+				}
+			}
+			Self::NoiseControl(status) => {
+				data.put_slice(&[0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0D]);
+				status.encode(data);
+				data.put_bytes(0x00, 3);
+			}
+			Self::EarDetection { primary, secondary } => {
+				data.put_slice(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00]);
+				primary.encode(data);
+				secondary.encode(data);
+			}
+			Self::Unknown(raw) => data.put_slice(raw),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+	NoiseControl(NoiseControlStatus),
+}
+
+impl Encode for ControlCommand {
+	fn encode(&self, data: &mut BytesMut) {
+		match self {
+			Self::NoiseControl(status) => {
+				data.put_u8(0x0D);
+				status.encode(data);
+				data.put_bytes(0x00, 3);
+			}
+		}
+	}
+}
+
+impl Decode for ControlCommand {
+	fn decode(data: &mut Bytes) -> Result<Self> {
+		if data.remaining() < 5 {
+			bail!("control command is too small");
+		}
+
+		let ret = match data.get_u8() {
+			0x0D => Self::NoiseControl(
+				NoiseControlStatus::decode(data).context("failed to parse noise control status")?,
+			),
+			x => bail!("invalid control command: {:x?}", x),
+		};
+
+		if data.split_to(3).as_ref() != [0x00, 0x00, 0x00] {
+			bail!("control command padding is not zeroed");
+		}
+
+		Ok(ret)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutgoingPacket {
+	Handshake,
+	EnableFeatures,
+	SubscribeNotifications,
+	Control(ControlCommand),
+}
+
+impl OutgoingPacket {
+	pub fn to_bytes(self) -> Bytes {
+		let mut data = BytesMut::with_capacity(16);
+		self.encode(&mut data);
+		data.freeze()
+	}
+
+	pub fn decode(mut data: Bytes) -> Result<Self> {
+		if data.remaining() < 6 {
+			bail!("packet is too small");
+		}
+
+		let ret = match (data.split_to(6).as_ref(), data.as_ref()) {
+			(
+				[0x00, 0x00, 0x04, 0x00, 0x01, 0x00],
+				[0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+			) => Self::Handshake,
+			(
+				[0x04, 0x00, 0x04, 0x00, 0x4D, 0x00],
+				[0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+			) => Self::EnableFeatures,
+			([0x04, 0x00, 0x04, 0x00, 0x0F, 0x00], [0xFF, 0xFF, 0xFF, 0xFF]) => {
+				Self::SubscribeNotifications
+			}
+			([0x04, 0x00, 0x04, 0x00, 0x09, 0x00], _) => {
+				let command =
+					ControlCommand::decode(&mut data).context("failed to parse control command")?;
+				if data.has_remaining() {
+					bail!("trailing data after control command: {:x?}", data);
+				}
+				Self::Control(command)
+			}
+			(x, _) => bail!("unknown outgoing packet {:x?}: {:x?}", x, data),
+		};
+
+		Ok(ret)
+	}
+}
+
+impl Encode for OutgoingPacket {
+	fn encode(&self, data: &mut BytesMut) {
+		match self {
+			Self::Handshake => data.put_slice(&[
+				0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+				0x00, 0x00,
+			]),
+			Self::EnableFeatures => data.put_slice(&[
+				0x04, 0x00, 0x04, 0x00, 0x4D, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+			]),
+			Self::SubscribeNotifications => {
+				data.put_slice(&[0x04, 0x00, 0x04, 0x00, 0x0F, 0x00, 0xFF, 0xFF, 0xFF, 0xFF])
+			}
+			Self::Control(command) => {
+				data.put_slice(&[0x04, 0x00, 0x04, 0x00, 0x09, 0x00]);
+				command.encode(data);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bytes::Bytes;
+
+	use super::{
+		Battery, BatteryComponent, BatteryStatus, ControlCommand, DecodeError, EarDetectionStatus,
+		NoiseControlStatus, OutgoingPacket, ParsedPacket,
+	};
+
+	fn all_outgoing() -> Vec<OutgoingPacket> {
+		let mut packets = vec![
+			OutgoingPacket::Handshake,
+			OutgoingPacket::EnableFeatures,
+			OutgoingPacket::SubscribeNotifications,
+		];
+		for status in [
+			NoiseControlStatus::Off,
+			NoiseControlStatus::NoiseCancellation,
+			NoiseControlStatus::Transparency,
+			NoiseControlStatus::AdaptiveTransparency,
+		] {
+			packets.push(OutgoingPacket::Control(ControlCommand::NoiseControl(
+				status,
+			)));
+		}
+		packets
+	}
+
+	#[test]
+	fn outgoing_round_trip() {
+		for packet in all_outgoing() {
+			let decoded = OutgoingPacket::decode(packet.to_bytes()).unwrap();
+			assert_eq!(decoded, packet);
+		}
+	}
+
+	#[test]
+	fn outgoing_wire_format() {
+		assert_eq!(
+			OutgoingPacket::Handshake.to_bytes().as_ref(),
+			[
+				0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+				0x00, 0x00
+			]
+		);
+		assert_eq!(
+			OutgoingPacket::Control(ControlCommand::NoiseControl(
+				NoiseControlStatus::NoiseCancellation
+			))
+			.to_bytes()
+			.as_ref(),
+			[
+				0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0D, 0x02, 0x00, 0x00, 0x00
+			]
+		);
+	}
+
+	#[test]
+	fn noise_control_matches_incoming() {
+		// the device reports noise control changes with the same layout as the control command
+		for packet in all_outgoing() {
+			if let OutgoingPacket::Control(ControlCommand::NoiseControl(status)) = packet {
+				match ParsedPacket::decode(packet.to_bytes()).unwrap() {
+					ParsedPacket::NoiseControl(x) => assert_eq!(x, status),
+					x => panic!("unexpected packet {:?}", x),
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn incoming_round_trip() {
+		let packets = [
+			ParsedPacket::Battery(vec![
+				Battery {
+					component: BatteryComponent::Left,
+					status: BatteryStatus::Discharging(80),
+				},
+				Battery {
+					component: BatteryComponent::Right,
+					status: BatteryStatus::Disconnected,
+				},
+				Battery {
+					component: BatteryComponent::Case,
+					status: BatteryStatus::Charging(40),
+				},
+			]),
+			ParsedPacket::NoiseControl(NoiseControlStatus::AdaptiveTransparency),
+			ParsedPacket::EarDetection {
+				primary: EarDetectionStatus::OutOfEar,
+				secondary: EarDetectionStatus::InCase,
+			},
+			ParsedPacket::Unknown(Bytes::from_static(&[
+				0x04, 0x00, 0x04, 0x00, 0x2B, 0x00, 0x01, 0x02,
+			])),
+		];
+		for packet in packets {
+			assert_eq!(ParsedPacket::decode(packet.to_bytes()).unwrap(), packet);
+		}
+	}
+
+	#[test]
+	fn unknown_packets_keep_raw_bytes() {
+		let raw = Bytes::from_static(&[0x04, 0x00, 0x04, 0x00, 0x2B, 0x00, 0x01, 0x02]);
+		match ParsedPacket::decode(raw.clone()).unwrap() {
+			ParsedPacket::Unknown(x) => assert_eq!(x, raw),
+			x => panic!("unexpected packet {:?}", x),
+		}
+
+		// battery packet with an unseen status byte
+		assert!(
+			ParsedPacket::decode(Bytes::from_static(&[
+				0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x01, 0x02, 0x01, 0x64, 0x09, 0x01
+			]))
+			.is_err()
+		);
+	}
+
+	#[test]
+	fn decode_error_offsets() {
+		let offset = |data: &'static [u8]| {
+			ParsedPacket::decode(Bytes::from_static(data))
+				.unwrap_err()
+				.downcast::<DecodeError>()
+				.unwrap()
+				.offset
+		};
+		assert_eq!(offset(&[0x04, 0x00]), 0);
+		// missing battery count
+		assert_eq!(offset(&[0x04, 0x00, 0x04, 0x00, 0x04, 0x00]), 6);
+		// invalid status of the second battery
+		assert_eq!(
+			offset(&[
+				0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x02, 0x04, 0x01, 0x50, 0x02, 0x01, 0x02, 0x01,
+				0x50, 0x09, 0x01
+			]),
+			14
+		);
+		// bad spacer after the status
+		assert_eq!(
+			offset(&[
+				0x04, 0x00, 0x04, 0x00, 0x04, 0x00, 0x01, 0x04, 0x01, 0x50, 0x02, 0x07
+			]),
+			11
+		);
+		// invalid secondary ear detection status
+		assert_eq!(offset(&[0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x09]), 7);
+	}
+
+	#[test]
+	fn outgoing_rejects_garbage() {
+		assert!(OutgoingPacket::decode(Bytes::from_static(&[0x04, 0x00])).is_err());
+		assert!(
+			OutgoingPacket::decode(Bytes::from_static(&[
+				0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0D, 0x07, 0x00, 0x00, 0x00
+			]))
+			.is_err()
+		);
+		assert!(
+			OutgoingPacket::decode(Bytes::from_static(&[
+				0x04, 0x00, 0x04, 0x00, 0x09, 0x00, 0x0D, 0x02, 0x00
+			]))
+			.is_err()
+		);
+	}
+}
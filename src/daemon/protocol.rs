@@ -0,0 +1,106 @@
+//! Line-delimited JSON protocol spoken over the daemon's unix socket.
+//!
+//! Every line a client sends is a [`Request`]. The first request on a connection must be
+//! [`Command::Hello`]; the daemon rejects everything else until the handshake succeeds, and
+//! rejects the handshake itself if the client speaks an incompatible version. Every line the
+//! daemon sends is a [`ServerMessage`]: either the [`Response`] to a request, carrying the same
+//! id, or an unsolicited [`Event`] for clients that subscribed to updates.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+	Address, PacketStats, PodsStatus, battery::BatteryPolicy, packet::NoiseControlStatus,
+	supervisor::TaskHealth,
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request {
+	pub id: u64,
+	#[serde(flatten)]
+	pub command: Command,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+	Hello { version: u32 },
+	GetStatus,
+	Subscribe,
+	Unsubscribe,
+	SetNoiseControl { mode: NoiseControlStatus },
+	GetPacketStats,
+	GetHealth,
+	ListDevices,
+	GetBatteryPolicy,
+	SetBatteryPolicy { policy: BatteryPolicy },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+	Hello { version: u32 },
+	Status { status: PodsStatus },
+	PacketStats { stats: PacketStats },
+	Health { tasks: BTreeMap<String, TaskHealth> },
+	Devices { devices: Vec<DeviceSummary> },
+	BatteryPolicy { policy: BatteryPolicy },
+	Ok,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceSummary {
+	pub address: Address,
+	pub status: PodsStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+	Status { status: PodsStatus },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum ProtocolError {
+	InvalidRequest { message: String },
+	UnsupportedVersion { supported: u32 },
+	HandshakeRequired,
+	DeviceNotConnected,
+	DeviceError { message: String },
+}
+
+impl Display for ProtocolError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::InvalidRequest { message } => write!(f, "invalid request: {message}"),
+			Self::UnsupportedVersion { supported } => {
+				write!(
+					f,
+					"unsupported protocol version, daemon speaks version {supported}"
+				)
+			}
+			Self::HandshakeRequired => write!(f, "hello handshake is required first"),
+			Self::DeviceNotConnected => write!(f, "device is not connected"),
+			Self::DeviceError { message } => write!(f, "device error: {message}"),
+		}
+	}
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServerMessage {
+	Response {
+		/// Id of the request this is a response to, or `None` if the request was unparseable.
+		id: Option<u64>,
+		result: Result<Response, ProtocolError>,
+	},
+	Event {
+		event: Event,
+	},
+}
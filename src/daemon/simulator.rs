@@ -0,0 +1,79 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use tokio::time::sleep;
+
+use super::{
+	Address, CommandReceiver, DecodePolicy, DeviceHandle,
+	bluetooth::{handle_stream, set_disconnected, without_device},
+	capture::{Capture, Recorded},
+	transport::{StreamTarget, StreamTransport, Transport},
+};
+
+/// Address of the simulated device when none is given, from the range reserved for
+/// documentation.
+pub const DEFAULT_ADDRESS: &str = "00:00:5E:00:53:01";
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Abstract unix socket that `airpodsd simulate` listens on for the device with `addr`.
+pub fn socket_name(addr: Address) -> String {
+	format!("\0dev.r58playz.airpodsd.simulator.{addr}")
+}
+
+/// Talks to a device played by `airpodsd simulate` instead of connecting over bluetooth, and
+/// connects again whenever the simulator goes away.
+pub async fn simulator_main(
+	handle: DeviceHandle,
+	commands: CommandReceiver,
+	policy: DecodePolicy,
+	capture: Option<Arc<Capture>>,
+) -> Result<()> {
+	let DeviceHandle {
+		addr,
+		status,
+		notify,
+		stats,
+		..
+	} = handle;
+	let mut commands = commands.lock().await;
+
+	let mut was_waiting = false;
+	loop {
+		let target = StreamTarget::Unix(socket_name(addr));
+		match Recorded::<StreamTransport>::connect((target, addr, capture.clone())).await {
+			Ok(mut transport) => {
+				was_waiting = false;
+				info!("connected to simulated device {}", addr);
+				status.lock().await.connected = true;
+				notify.notify(usize::MAX);
+
+				handle_stream(
+					&mut transport,
+					status.clone(),
+					notify.clone(),
+					&mut commands,
+					policy,
+					&stats,
+				)
+				.await
+				.context("failed to handle simulated device stream")?;
+				info!("simulated device disconnected");
+				if let Err(err) = transport.close().await {
+					debug!("failed to close simulator connection: {:?}", err);
+				}
+			}
+			Err(err) if !was_waiting => {
+				info!("waiting for the simulator for {}: {}", addr, err);
+			}
+			Err(_) => {}
+		}
+
+		if !was_waiting {
+			set_disconnected(&status, &notify).await;
+			was_waiting = true;
+		}
+		without_device(&mut commands, sleep(RETRY_INTERVAL)).await;
+	}
+}
@@ -0,0 +1,189 @@
+use std::{collections::BTreeMap, fmt::Display, future::Future, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, task::JoinSet, time::Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// A task that ran for at least this long before failing starts over with the initial backoff.
+const HEALTHY_RUNTIME: Duration = Duration::from_secs(60);
+
+/// Marks an error as caused by the configuration, which restarting the task will not fix.
+#[derive(Debug)]
+pub struct ConfigError(pub &'static str);
+
+impl Display for ConfigError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+	Running,
+	Restarting,
+	Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct TaskHealth {
+	pub state: TaskState,
+	pub restarts: u32,
+	pub last_error: Option<String>,
+}
+
+pub type HealthState = Arc<Mutex<BTreeMap<String, TaskHealth>>>;
+
+fn jitter(backoff: Duration) -> Duration {
+	// +-25% so that tasks failing for the same reason don't retry in lockstep
+	backoff.mul_f64(0.75 + fastrand::f64() * 0.5)
+}
+
+async fn set_health(health: &HealthState, name: &str, update: impl FnOnce(&mut TaskHealth)) {
+	let mut locked = health.lock().await;
+	let entry = locked.entry(name.to_string()).or_insert(TaskHealth {
+		state: TaskState::Running,
+		restarts: 0,
+		last_error: None,
+	});
+	update(entry);
+}
+
+async fn supervise<F, Fut>(name: String, task: F, health: HealthState) -> Result<()>
+where
+	F: Fn() -> Fut,
+	Fut: Future<Output = Result<()>> + Send + 'static,
+{
+	let mut backoff = INITIAL_BACKOFF;
+	loop {
+		set_health(&health, &name, |x| x.state = TaskState::Running).await;
+		let started = Instant::now();
+
+		let err = match tokio::spawn(task()).await {
+			Ok(Ok(())) => anyhow!("task exited unexpectedly"),
+			Ok(Err(err)) => err,
+			Err(err) => anyhow!(err).context("task panicked"),
+		};
+
+		if err.downcast_ref::<ConfigError>().is_some() {
+			error!("{} failed with an unrecoverable error: {:?}", name, err);
+			set_health(&health, &name, |x| {
+				x.state = TaskState::Failed;
+				x.last_error = Some(format!("{err:#}"));
+			})
+			.await;
+			return Err(err).with_context(|| format!("{name} failed"));
+		}
+
+		if started.elapsed() >= HEALTHY_RUNTIME {
+			backoff = INITIAL_BACKOFF;
+		}
+		let delay = jitter(backoff);
+		warn!("{} failed, restarting in {:?}: {:?}", name, delay, err);
+		set_health(&health, &name, |x| {
+			x.state = TaskState::Restarting;
+			x.restarts += 1;
+			x.last_error = Some(format!("{err:#}"));
+		})
+		.await;
+
+		tokio::time::sleep(delay).await;
+		backoff = (backoff * 2).min(MAX_BACKOFF);
+		info!("restarting {}", name);
+	}
+}
+
+/// Runs the daemon's subsystems, restarting them with exponential backoff when they fail.
+pub struct Supervisor {
+	set: JoinSet<Result<()>>,
+	health: HealthState,
+}
+
+impl Supervisor {
+	pub fn new(health: HealthState) -> Self {
+		Self {
+			set: JoinSet::new(),
+			health,
+		}
+	}
+
+	pub fn spawn<F, Fut>(&mut self, name: impl Into<String>, task: F)
+	where
+		F: Fn() -> Fut + Send + 'static,
+		Fut: Future<Output = Result<()>> + Send + 'static,
+	{
+		self.set
+			.spawn(supervise(name.into(), task, self.health.clone()));
+	}
+
+	/// Waits until a task fails with an unrecoverable error.
+	pub async fn wait(mut self) -> Result<()> {
+		while let Some(ret) = self.set.join_next().await {
+			ret.context("failed to wait for task")??;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::BTreeMap,
+		sync::{
+			Arc,
+			atomic::{AtomicU32, Ordering},
+		},
+	};
+
+	use anyhow::{Context, bail};
+	use tokio::sync::Mutex;
+
+	use super::{ConfigError, Supervisor, TaskState};
+
+	#[tokio::test]
+	async fn config_errors_are_not_retried() {
+		let health = Arc::new(Mutex::new(BTreeMap::new()));
+		let runs = Arc::new(AtomicU32::new(0));
+		let mut supervisor = Supervisor::new(health.clone());
+
+		supervisor.spawn("broken", {
+			let runs = runs.clone();
+			move || {
+				runs.fetch_add(1, Ordering::SeqCst);
+				async { Err::<(), _>(anyhow::anyhow!("bad path")).context(ConfigError("invalid")) }
+			}
+		});
+
+		assert!(supervisor.wait().await.is_err());
+		assert_eq!(runs.load(Ordering::SeqCst), 1);
+		assert_eq!(health.lock().await["broken"].state, TaskState::Failed);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn transient_errors_are_retried() {
+		let health = Arc::new(Mutex::new(BTreeMap::new()));
+		let runs = Arc::new(AtomicU32::new(0));
+		let mut supervisor = Supervisor::new(health.clone());
+
+		supervisor.spawn("flaky", {
+			let runs = runs.clone();
+			move || {
+				let run = runs.fetch_add(1, Ordering::SeqCst);
+				async move {
+					if run == 0 {
+						bail!("host is down");
+					}
+					Err(anyhow::anyhow!("giving up")).context(ConfigError("done"))
+				}
+			}
+		});
+
+		assert!(supervisor.wait().await.is_err());
+		assert_eq!(runs.load(Ordering::SeqCst), 2);
+		assert_eq!(health.lock().await["flaky"].restarts, 1);
+	}
+}
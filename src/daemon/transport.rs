@@ -0,0 +1,158 @@
+use std::{future::Future, io};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use tokio::{
+	io::AsyncWriteExt,
+	net::{
+		UnixStream,
+		unix::{OwnedReadHalf, OwnedWriteHalf},
+	},
+};
+use tokio_util::codec::FramedRead;
+
+use super::{
+	blconn::{self, L2CapAddr},
+	codec::AapCodec,
+};
+
+/// A connection to a device that carries whole AAP frames.
+pub trait Transport: Send {
+	/// What [`Transport::connect`] connects to.
+	type Target: Send;
+
+	/// Opens a connection to the device at `target`.
+	fn connect(target: Self::Target) -> impl Future<Output = io::Result<Self>> + Send
+	where
+		Self: Sized;
+
+	/// Reads the next frame, or `None` once the device closed the connection.
+	///
+	/// This has to be cancel safe, as it is raced against commands for the device.
+	fn read_frame(&mut self) -> impl Future<Output = io::Result<Option<Bytes>>> + Send;
+
+	fn write_frame(&mut self, frame: Bytes) -> impl Future<Output = io::Result<()>> + Send;
+
+	/// Closes the connection, after which the device sees the connection as closed.
+	fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// Where a [`StreamTransport`] connects to.
+pub enum StreamTarget {
+	/// L2CAP connection to `addr` from the adapter with the address in `local`.
+	L2Cap { local: L2CapAddr, addr: L2CapAddr },
+	/// Unix socket, such as the one that the simulator listens on.
+	Unix(String),
+}
+
+/// AAP over a stream socket, such as an L2CAP connection or a connection to the simulator.
+pub struct StreamTransport {
+	frames: FramedRead<OwnedReadHalf, AapCodec>,
+	tx: OwnedWriteHalf,
+}
+
+impl From<UnixStream> for StreamTransport {
+	fn from(value: UnixStream) -> Self {
+		let (rx, tx) = value.into_split();
+		Self {
+			frames: FramedRead::new(rx, AapCodec),
+			tx,
+		}
+	}
+}
+
+impl Transport for StreamTransport {
+	type Target = StreamTarget;
+
+	async fn connect(target: StreamTarget) -> io::Result<Self> {
+		match target {
+			StreamTarget::L2Cap { local, addr } => blconn::connect(local, addr).await,
+			StreamTarget::Unix(path) => UnixStream::connect(path).await.map(Self::from),
+		}
+	}
+
+	async fn read_frame(&mut self) -> io::Result<Option<Bytes>> {
+		self.frames.next().await.transpose()
+	}
+
+	async fn write_frame(&mut self, frame: Bytes) -> io::Result<()> {
+		self.tx.write_all(&frame).await
+	}
+
+	async fn close(&mut self) -> io::Result<()> {
+		self.tx.shutdown().await
+	}
+}
+
+#[cfg(test)]
+pub use memory::{MemoryDevice, MemoryTransport};
+
+#[cfg(test)]
+mod memory {
+	use std::io;
+
+	use bytes::Bytes;
+	use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+	use super::Transport;
+
+	/// In-memory transport whose device side is driven by [`MemoryDevice`].
+	pub struct MemoryTransport {
+		rx: UnboundedReceiver<io::Result<Bytes>>,
+		tx: Option<UnboundedSender<Bytes>>,
+	}
+
+	/// Device side of a [`MemoryTransport`].
+	pub struct MemoryDevice {
+		/// Frames written by the daemon, ends once the transport is closed or dropped.
+		pub rx: UnboundedReceiver<Bytes>,
+		/// Frames or read errors for the daemon, dropping it closes the connection.
+		pub tx: UnboundedSender<io::Result<Bytes>>,
+	}
+
+	impl MemoryTransport {
+		pub fn pair() -> (Self, MemoryDevice) {
+			let (host_tx, device_rx) = unbounded_channel();
+			let (device_tx, host_rx) = unbounded_channel();
+			(
+				Self {
+					rx: host_rx,
+					tx: Some(host_tx),
+				},
+				MemoryDevice {
+					rx: device_rx,
+					tx: device_tx,
+				},
+			)
+		}
+	}
+
+	impl Transport for MemoryTransport {
+		/// Receives the device side of every connection, like a listening socket.
+		type Target = UnboundedSender<MemoryDevice>;
+
+		async fn connect(target: UnboundedSender<MemoryDevice>) -> io::Result<Self> {
+			let (transport, device) = Self::pair();
+			target
+				.send(device)
+				.map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+			Ok(transport)
+		}
+
+		async fn read_frame(&mut self) -> io::Result<Option<Bytes>> {
+			self.rx.recv().await.transpose()
+		}
+
+		async fn write_frame(&mut self, frame: Bytes) -> io::Result<()> {
+			self.tx
+				.as_ref()
+				.and_then(|x| x.send(frame).ok())
+				.ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+		}
+
+		async fn close(&mut self) -> io::Result<()> {
+			self.tx.take();
+			Ok(())
+		}
+	}
+}
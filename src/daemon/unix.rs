@@ -1,16 +1,22 @@
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use event_listener::Event;
 use log::{info, warn};
+use serde::Serialize;
 use tokio::{
 	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
 	net::{UnixListener, UnixStream, unix::OwnedWriteHalf},
 	select,
-	sync::Mutex,
 };
 
-use super::{Address, PodsState, PodsStatus};
+use super::{
+	DaemonState, DeviceCommand, DeviceHandle,
+	protocol::{
+		self, Command, DeviceSummary, PROTOCOL_VERSION, ProtocolError, Request, Response,
+		ServerMessage,
+	},
+	supervisor::ConfigError,
+};
 
 enum ListenerEvent {
 	ReadLine(String),
@@ -18,26 +24,123 @@ enum ListenerEvent {
 	Exit,
 }
 
-fn serialize_status(status: &PodsStatus) -> Result<Vec<u8>> {
-	let mut vec = serde_json::to_vec(&status).context("failed to serialize status")?;
+struct Client {
+	tx: OwnedWriteHalf,
+	greeted: bool,
+	subscribed: bool,
+}
+
+fn serialize_line(message: &impl Serialize) -> Result<Vec<u8>> {
+	let mut vec = serde_json::to_vec(message).context("failed to serialize message")?;
 	vec.extend_from_slice(b"\n");
 	Ok(vec)
 }
 
-async fn write_status(tx: &mut OwnedWriteHalf, status: &Mutex<PodsStatus>) -> Result<()> {
-	tx.write_all(&serialize_status(&*status.lock().await)?)
+async fn write_message(tx: &mut OwnedWriteHalf, message: &ServerMessage) -> Result<()> {
+	tx.write_all(&serialize_line(message)?)
 		.await
-		.context("failed to write initial status to listener")
+		.context("failed to write message to listener")
+}
+
+async fn run_command(
+	client: &mut Client,
+	handle: &DeviceHandle,
+	state: &DaemonState,
+	command: Command,
+) -> Result<Response, ProtocolError> {
+	match command {
+		Command::Hello { version } => {
+			if version != PROTOCOL_VERSION {
+				return Err(ProtocolError::UnsupportedVersion {
+					supported: PROTOCOL_VERSION,
+				});
+			}
+			client.greeted = true;
+			Ok(Response::Hello {
+				version: PROTOCOL_VERSION,
+			})
+		}
+		_ if !client.greeted => Err(ProtocolError::HandshakeRequired),
+		Command::GetStatus => Ok(Response::Status {
+			status: *handle.status.lock().await,
+		}),
+		Command::Subscribe => {
+			client.subscribed = true;
+			Ok(Response::Ok)
+		}
+		Command::Unsubscribe => {
+			client.subscribed = false;
+			Ok(Response::Ok)
+		}
+		Command::SetNoiseControl { mode } => {
+			handle.run(DeviceCommand::SetNoiseControl(mode)).await?;
+			Ok(Response::Ok)
+		}
+		Command::GetPacketStats => Ok(Response::PacketStats {
+			stats: *handle.stats.lock().await,
+		}),
+		Command::GetHealth => Ok(Response::Health {
+			tasks: state.health.lock().await.clone(),
+		}),
+		Command::ListDevices => {
+			let mut devices = Vec::with_capacity(state.devices.len());
+			for device in &state.devices {
+				devices.push(DeviceSummary {
+					address: device.addr,
+					status: *device.status.lock().await,
+				});
+			}
+			Ok(Response::Devices { devices })
+		}
+		Command::GetBatteryPolicy => Ok(Response::BatteryPolicy {
+			policy: *state.battery_policy.lock().await,
+		}),
+		Command::SetBatteryPolicy { policy } => {
+			info!("setting battery policy to {:?}", policy);
+			*state.battery_policy.lock().await = policy;
+			state.settings_changed.notify(usize::MAX);
+			Ok(Response::BatteryPolicy { policy })
+		}
+	}
+}
+
+async fn handle_line(
+	client: &mut Client,
+	handle: &DeviceHandle,
+	state: &DaemonState,
+	line: &str,
+) -> Result<()> {
+	let (id, result) = match serde_json::from_str::<Request>(line) {
+		Ok(Request { id, command }) => {
+			(Some(id), run_command(client, handle, state, command).await)
+		}
+		Err(err) => (
+			None,
+			Err(ProtocolError::InvalidRequest {
+				message: err.to_string(),
+			}),
+		),
+	};
+
+	if let Err(err) = &result {
+		warn!("request {:?} failed: {}", line, err);
+	}
+
+	write_message(&mut client.tx, &ServerMessage::Response { id, result }).await
 }
 
 async fn handle_listener(
 	conn: UnixStream,
-	status: PodsState,
-	notify: Arc<Event>,
+	handle: DeviceHandle,
+	state: Arc<DaemonState>,
 ) -> Result<()> {
-	let (rx, mut tx) = conn.into_split();
+	let (rx, tx) = conn.into_split();
 	let mut rx = BufReader::new(rx).lines();
-	write_status(&mut tx, &status).await?;
+	let mut client = Client {
+		tx,
+		greeted: false,
+		subscribed: false,
+	};
 
 	loop {
 		match select! {
@@ -47,14 +150,24 @@ async fn handle_listener(
 					None => ListenerEvent::Exit,
 				}
 			},
-			_ = notify.listen() => {
+			_ = handle.notify.listen() => {
 				ListenerEvent::Update
 			}
 		} {
 			ListenerEvent::ReadLine(x) => {
-				warn!("ignoring received line {:?}", x);
+				handle_line(&mut client, &handle, &state, &x).await?;
 			}
-			ListenerEvent::Update => write_status(&mut tx, &status).await?,
+			ListenerEvent::Update if client.subscribed => {
+				let status = *handle.status.lock().await;
+				write_message(
+					&mut client.tx,
+					&ServerMessage::Event {
+						event: protocol::Event::Status { status },
+					},
+				)
+				.await?;
+			}
+			ListenerEvent::Update => {}
 			ListenerEvent::Exit => break,
 		}
 	}
@@ -62,18 +175,134 @@ async fn handle_listener(
 	Ok(())
 }
 
-pub async fn unix_listener_main(
-	addr: Address,
-	status: PodsState,
-	notify: Arc<Event>,
-) -> Result<()> {
-	let sock = UnixListener::bind(format!("\0dev.r58playz.airpodsd.{addr}"))
-		.context("failed to bind to unix socket")?;
+pub async fn unix_listener_main(handle: DeviceHandle, state: Arc<DaemonState>) -> Result<()> {
+	let sock = UnixListener::bind(format!("\0dev.r58playz.airpodsd.{}", handle.addr))
+		.context(ConfigError("failed to bind to unix socket"))?;
 
 	while let Ok((conn, addr)) = sock.accept().await {
 		info!("accepted client at addr {:?}", addr);
-		tokio::spawn(handle_listener(conn, status.clone(), notify.clone()));
+		tokio::spawn(handle_listener(conn, handle.clone(), state.clone()));
 	}
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use event_listener::Event;
+	use tokio::{
+		io::{AsyncBufReadExt, BufReader, Lines},
+		net::{UnixStream, unix::OwnedReadHalf},
+		sync::Mutex,
+	};
+
+	use super::{Client, handle_line};
+	use crate::daemon::{
+		DaemonState, DeviceHandle,
+		battery::BatteryPolicy,
+		device_handles,
+		protocol::{PROTOCOL_VERSION, ProtocolError, Response, ServerMessage},
+	};
+
+	struct Harness {
+		client: Client,
+		rx: Lines<BufReader<OwnedReadHalf>>,
+		handle: DeviceHandle,
+		state: DaemonState,
+	}
+
+	impl Harness {
+		fn new() -> Self {
+			let (ours, theirs) = UnixStream::pair().unwrap();
+			let (devices, _) = device_handles(vec!["AA:BB:CC:DD:EE:FF".parse().unwrap()]).unwrap();
+			Self {
+				client: Client {
+					tx: ours.into_split().1,
+					greeted: false,
+					subscribed: false,
+				},
+				rx: BufReader::new(theirs.into_split().0).lines(),
+				handle: devices[0].clone(),
+				state: DaemonState {
+					devices,
+					health: Default::default(),
+					battery_policy: Mutex::new(BatteryPolicy::default()),
+					settings_changed: Event::new(),
+				},
+			}
+		}
+
+		/// Sends `line` and returns the id and result of the response.
+		async fn request(&mut self, line: &str) -> (Option<u64>, Result<Response, ProtocolError>) {
+			handle_line(&mut self.client, &self.handle, &self.state, line)
+				.await
+				.unwrap();
+			let line = self.rx.next_line().await.unwrap().unwrap();
+			match serde_json::from_str(&line).unwrap() {
+				ServerMessage::Response { id, result } => (id, result),
+				message => panic!("expected a response, got {:?}", message),
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn handshake_is_required() {
+		let mut harness = Harness::new();
+		let (id, result) = harness
+			.request(r#"{"id": 1, "command": "get_status"}"#)
+			.await;
+		assert_eq!(id, Some(1));
+		assert_eq!(result.unwrap_err(), ProtocolError::HandshakeRequired);
+
+		let hello = format!(r#"{{"id": 2, "command": "hello", "version": {PROTOCOL_VERSION}}}"#);
+		let (id, result) = harness.request(&hello).await;
+		assert_eq!(id, Some(2));
+		assert!(matches!(result, Ok(Response::Hello { version }) if version == PROTOCOL_VERSION));
+
+		let (id, result) = harness
+			.request(r#"{"id": 3, "command": "get_status"}"#)
+			.await;
+		assert_eq!(id, Some(3));
+		assert!(matches!(result, Ok(Response::Status { .. })));
+	}
+
+	#[tokio::test]
+	async fn other_versions_are_rejected() {
+		let mut harness = Harness::new();
+		let hello = format!(
+			r#"{{"id": 7, "command": "hello", "version": {}}}"#,
+			PROTOCOL_VERSION + 1
+		);
+		let (id, result) = harness.request(&hello).await;
+		assert_eq!(id, Some(7));
+		assert_eq!(
+			result.unwrap_err(),
+			ProtocolError::UnsupportedVersion {
+				supported: PROTOCOL_VERSION
+			}
+		);
+
+		// the failed handshake doesn't count
+		let (_, result) = harness
+			.request(r#"{"id": 8, "command": "get_status"}"#)
+			.await;
+		assert_eq!(result.unwrap_err(), ProtocolError::HandshakeRequired);
+	}
+
+	#[tokio::test]
+	async fn unparseable_lines_have_no_id() {
+		let mut harness = Harness::new();
+		for line in [
+			"not json",
+			r#"{"id": 4, "command": "reboot"}"#,
+			r#"{"command": "hello"}"#,
+		] {
+			let (id, result) = harness.request(line).await;
+			assert_eq!(id, None, "{line}");
+			assert!(
+				matches!(result, Err(ProtocolError::InvalidRequest { .. })),
+				"{line}"
+			);
+		}
+	}
+}
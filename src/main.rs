@@ -1,7 +1,12 @@
-use anyhow::Result;
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
 use clap::{Parser, Subcommand};
-use client::status;
-use daemon::{Address, daemon_main};
+use client::{decode, health, history, noise, parse, policy, simulate, status};
+use daemon::{
+	Address, DecodePolicy, battery::BatteryPolicy, config::Config, daemon_main, dbus::DbusBus,
+	discovery::find_device, mpris::PauseOn, simulator,
+};
 
 mod client;
 mod daemon;
@@ -10,37 +15,203 @@ mod daemon;
 struct Cli {
 	#[command(subcommand)]
 	command: Commands,
+	/// Bluetooth adapter to use instead of the default one, such as `hci1`.
+	#[arg(short, long, global = true)]
+	adapter: Option<String>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
 	/// Run the daemon.
-	#[command(arg_required_else_help = true)]
-	Daemon { mac_address: Address },
+	Daemon {
+		/// Devices to manage, in addition to the ones in the config file. If there are none, the
+		/// only paired AirPods are managed.
+		mac_addresses: Vec<Address>,
+		/// Manage every paired AirPods.
+		#[arg(long, conflicts_with_all = ["simulate", "replay"])]
+		all_paired: bool,
+		/// Path to the config file, defaults to `$XDG_CONFIG_HOME/airpodsd/config.toml`.
+		#[arg(short, long)]
+		config: Option<PathBuf>,
+		/// How to handle packets from the device that fail to decode.
+		#[arg(long, value_enum)]
+		decode_policy: Option<DecodePolicy>,
+		/// How the battery levels are combined into the percentage reported to bluez.
+		#[arg(long, value_enum)]
+		battery_policy: Option<BatteryPolicy>,
+		/// Bus to publish the `dev.r58playz.airpodsd.Device1` service on.
+		#[arg(long, value_enum)]
+		dbus_bus: Option<DbusBus>,
+		/// When to pause media players as buds are taken out of the ears.
+		#[arg(long, value_enum)]
+		pause_on: Option<PauseOn>,
+		/// Connect to devices played by `airpodsd simulate` instead of using bluetooth.
+		#[arg(long)]
+		simulate: bool,
+		/// Write every frame sent to or received from the devices to this capture file.
+		#[arg(long)]
+		record: Option<PathBuf>,
+		/// Feed the frames that the devices sent in this capture file into the daemon instead of
+		/// connecting to them.
+		#[arg(long, conflicts_with = "simulate")]
+		replay: Option<PathBuf>,
+	},
 	/// Watch or get the status of a device.
-	#[command(arg_required_else_help = true)]
 	Status {
-		mac_address: Address,
+		/// Defaults to the only paired AirPods.
+		mac_address: Option<Address>,
 		#[clap(short, long)]
 		watch: bool,
 	},
+	/// Set the noise control mode of a device.
+	#[command(arg_required_else_help = true)]
+	Noise {
+		mac_address: Address,
+		#[arg(value_enum)]
+		mode: noise::NoiseMode,
+	},
+	/// Show the recorded battery history of a device.
+	History {
+		/// Defaults to the only paired AirPods.
+		mac_address: Option<Address>,
+		/// Only show records newer than this, such as `12h` or `7d`.
+		#[arg(long, value_parser = history::parse_duration)]
+		since: Option<u64>,
+		/// Only show the changes of this component.
+		#[arg(long, value_enum)]
+		component: Option<history::Component>,
+		#[arg(long, value_enum, default_value_t)]
+		format: history::Format,
+	},
+	/// Show charge cycles and runtime trends of a device from its battery history.
+	Health {
+		/// Defaults to the only paired AirPods.
+		mac_address: Option<Address>,
+	},
+	/// Get or set how the battery levels are combined into the percentage reported to bluez.
+	#[command(arg_required_else_help = true)]
+	BatteryPolicy {
+		mac_address: Address,
+		#[arg(value_enum)]
+		policy: Option<BatteryPolicy>,
+	},
+	/// Print the AAP traffic in a btsnoop capture, such as one written by `btmon -w`.
+	#[command(arg_required_else_help = true)]
+	Decode { capture: PathBuf },
+	/// Decode a packet from the device given in hex, or one packet per line of stdin.
+	ParsePacket { hex: Vec<String> },
+	/// Play a device for `airpodsd daemon --simulate`, for development without bluetooth.
+	Simulate {
+		#[arg(default_value = simulator::DEFAULT_ADDRESS)]
+		mac_address: Address,
+		/// Script of steps to play, instead of reading them from stdin.
+		#[arg(short, long)]
+		script: Option<PathBuf>,
+	},
+}
+
+async fn resolve(mac_address: Option<Address>, adapter: Option<&str>) -> Result<Address> {
+	match mac_address {
+		Some(x) => Ok(x),
+		None => find_device(adapter).await,
+	}
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
 	let args = Cli::parse();
+	let ignores_adapter = matches!(
+		args.command,
+		Commands::Noise { .. }
+			| Commands::BatteryPolicy { .. }
+			| Commands::Decode { .. }
+			| Commands::ParsePacket { .. }
+			| Commands::Simulate { .. }
+	);
+	if ignores_adapter && args.adapter.is_some() {
+		bail!(
+			"--adapter is only used by the daemon and to find the device when no MAC address is given"
+		);
+	}
 
 	match args.command {
-		Commands::Daemon { mac_address } => {
-			daemon_main(mac_address).await?;
+		Commands::Daemon {
+			mac_addresses,
+			all_paired,
+			config,
+			decode_policy,
+			battery_policy,
+			dbus_bus,
+			pause_on,
+			simulate,
+			record,
+			replay,
+		} => {
+			let mut config = Config::load(config.as_deref())?;
+			config.devices.extend(mac_addresses);
+			config.all_paired |= all_paired;
+			config.simulate = simulate;
+			config.record = record;
+			config.replay = replay;
+			if let Some(adapter) = args.adapter {
+				config.adapter = Some(adapter);
+			}
+			if let Some(decode_policy) = decode_policy {
+				config.decode_policy = decode_policy;
+			}
+			if let Some(battery_policy) = battery_policy {
+				config.battery_policy = battery_policy;
+			}
+			if let Some(dbus_bus) = dbus_bus {
+				config.dbus_bus = dbus_bus;
+			}
+			if let Some(pause_on) = pause_on {
+				config.ear_detection.pause_on = pause_on;
+			}
+			daemon_main(config).await?;
 		}
 		Commands::Status { mac_address, watch } => {
+			let mac_address = resolve(mac_address, args.adapter.as_deref()).await?;
 			if watch {
 				status::watch(mac_address).await?;
 			} else {
 				status::get(mac_address).await?;
 			}
 		}
+		Commands::Noise { mac_address, mode } => {
+			noise::set(mac_address, mode).await?;
+		}
+		Commands::History {
+			mac_address,
+			since,
+			component,
+			format,
+		} => {
+			let mac_address = resolve(mac_address, args.adapter.as_deref()).await?;
+			history::show(mac_address, since, component, format)?;
+		}
+		Commands::Health { mac_address } => {
+			let mac_address = resolve(mac_address, args.adapter.as_deref()).await?;
+			health::show(mac_address)?;
+		}
+		Commands::BatteryPolicy {
+			mac_address,
+			policy: battery_policy,
+		} => {
+			policy::run(mac_address, battery_policy).await?;
+		}
+		Commands::Decode { capture } => {
+			decode::show(&capture)?;
+		}
+		Commands::ParsePacket { hex } => {
+			parse::run(&hex)?;
+		}
+		Commands::Simulate {
+			mac_address,
+			script,
+		} => {
+			simulate::run(mac_address, script.as_deref()).await?;
+		}
 	}
 
 	Ok(())